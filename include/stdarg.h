@@ -0,0 +1,21 @@
+// mycc組み込みのstdarg.h
+// va_listはSystem V ABIと同じ構造なので、vprintfなどのライブラリ関数にそのまま渡せる
+#ifndef __STDARG_H
+#define __STDARG_H
+
+typedef struct {
+    unsigned int gp_offset;
+    unsigned int fp_offset;
+    void *overflow_arg_area;
+    void *reg_save_area;
+} __va_elem;
+typedef __va_elem va_list[1];
+typedef va_list __gnuc_va_list; // glibcのヘッダが使う名前
+
+#define va_start(ap, param) __builtin_va_start(ap, param)
+#define va_arg(ap, type) __builtin_va_arg(ap, type)
+#define va_end(ap) __builtin_va_end(ap)
+#define va_copy(dest, src) __builtin_va_copy(dest, src)
+#define __va_copy(dest, src) __builtin_va_copy(dest, src)
+
+#endif
@@ -0,0 +1,7 @@
+// mycc組み込みのstdnoreturn.h
+#ifndef __STDNORETURN_H
+#define __STDNORETURN_H
+
+#define noreturn _Noreturn
+
+#endif
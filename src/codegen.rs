@@ -1,4 +1,69 @@
 use crate::parser::{NodeKind, Ast};
+use crate::ty::{Type, is_unsigned, unqual};
+
+// raxが指すアドレスから型tyの値を読み、raxに入れる
+// レジスタ上の整数は、符号付きなら符号拡張、符号なしならゼロ拡張した64ビットの値として扱う
+fn load(ty : &Type) {
+    match ty {
+        Type::Int | Type::Enum => println!("  movsxd rax, dword ptr [rax]"),
+        Type::UInt => println!("  mov eax, dword ptr [rax]"),
+        Type::Char => println!("  movsx rax, byte ptr [rax]"),
+        Type::Bool | Type::UChar => println!("  movzx eax, byte ptr [rax]"),
+        Type::Short => println!("  movsx rax, word ptr [rax]"),
+        Type::UShort => println!("  movzx eax, word ptr [rax]"),
+        Type::Array(..) | Type::Struct(_) | Type::Func(_) => (), // 配列と構造体と関数は先頭のアドレスのまま扱う
+        _ => println!("  mov rax, [rax]"),
+    }
+}
+
+// raxが指すアドレスにrdiの値を型tyとして書き込む
+// 構造体はrdiが指す構造体の内容を1バイトずつ複写する
+fn store(ty : &Type) {
+    match ty {
+        Type::Struct(info) => {
+            for i in 0..info.borrow().size {
+                println!("  mov r8b, [rdi + {}]", i);
+                println!("  mov [rax + {}], r8b", i);
+            }
+        }
+        Type::Int | Type::UInt | Type::Enum => println!("  mov [rax], edi"),
+        Type::Bool | Type::Char | Type::UChar => println!("  mov [rax], dil"),
+        Type::Short | Type::UShort => println!("  mov [rax], di"),
+        _ => println!("  mov [rax], rdi"),
+    }
+}
+
+// raxの値を型tyで表せる値に変換する(上位のビットを切り捨てて符号拡張またはゼロ拡張する)
+fn cast(ty : &Type) {
+    match ty {
+        Type::Bool => { // 0でなければ1
+            println!("  cmp rax, 0");
+            println!("  setne al");
+            println!("  movzx eax, al");
+        }
+        Type::Int | Type::Enum => println!("  movsxd rax, eax"),
+        Type::UInt => println!("  mov eax, eax"),
+        Type::Char => println!("  movsx rax, al"),
+        Type::UChar => println!("  movzx eax, al"),
+        Type::Short => println!("  movsx rax, ax"),
+        Type::UShort => println!("  movzx eax, ax"),
+        _ => (),
+    }
+}
+
+// 引数を渡すレジスタ(64ビット, 32ビット, 16ビット, 8ビット)
+const ARG_REGS : [[&str; 4]; 6] = [
+    ["rdi", "edi", "di", "dil"], ["rsi", "esi", "si", "sil"], ["rdx", "edx", "dx", "dl"],
+    ["rcx", "ecx", "cx", "cl"], ["r8", "r8d", "r8w", "r8b"], ["r9", "r9d", "r9w", "r9b"],
+];
+
+// スタックに積まれたn個の引数を、引数を渡すレジスタに取り出す
+fn pop_args(n : usize) {
+    for i in (0..n).rev() {
+        let [reg64, ..] = ARG_REGS[i];
+        println!("  pop {}", reg64);
+    }
+}
 
 pub fn generate_code(ast : &Ast, index : &usize, branch_num : &mut i32) {
     if ast.tree.len() <= *index { return; }
@@ -8,17 +73,19 @@ pub fn generate_code(ast : &Ast, index : &usize, branch_num : &mut i32) {
 
     match &node.kind {
         NodeKind::NDRet => {
-            generate_code(ast, node.indices.first().unwrap(), branch_num);
-            println!("  pop rax");
+            if node.indices[0] != usize::MAX {
+                generate_code(ast, node.indices.first().unwrap(), branch_num);
+                println!("  pop rax");
+            }
             println!("  mov rsp, rbp");
             println!("  pop rbp");
             println!("  ret");
             return;
         }
-        NodeKind::NDLVa(_) => { // When variable occurs in the context of expressions, the value is stored in the stack.
+        NodeKind::NDLVa(_) | NodeKind::NDMember(..) => { // When variable occurs in the context of expressions, the value is stored in the stack.
             generate_lval(ast, index, branch_num);
             println!("  pop rax");
-            println!("  mov rax, [rax]");
+            load(&node.ty);
             println!("  push rax");
             return;
         }
@@ -26,12 +93,37 @@ pub fn generate_code(ast : &Ast, index : &usize, branch_num : &mut i32) {
             generate_lval(ast, node.indices.first().unwrap(), branch_num); // -> rax
             generate_code(ast, node.indices.get(1).unwrap(), branch_num); // -> rdi
             println!("  pop rdi\n  pop rax");
-            println!("  mov [rax], rdi");
-            println!("  push rdi");
+            let ty = &ast.tree[*node.indices.first().unwrap()].ty;
+            store(ty);
+            // 代入式の値は、左辺の型に変換した値(構造体なら左辺のアドレス)
+            if let Type::Struct(_) = ty {
+                println!("  push rax");
+                return;
+            }
+            println!("  mov rax, rdi");
+            cast(ty);
+            println!("  push rax");
             return;
         }
         NodeKind::NDNum(val) => {
-            println!("  push {}", val);
+            if (i32::MIN as i64..=i32::MAX as i64).contains(val) {
+                println!("  push {}", val);
+            }
+            else { // pushの即値は32ビットまで
+                println!("  mov rax, {}", val);
+                println!("  push rax");
+            }
+            return;
+        }
+        NodeKind::NDCast => {
+            generate_code(ast, node.indices.first().unwrap(), branch_num);
+            println!("  pop rax");
+            cast(&node.ty);
+            println!("  push rax");
+            return;
+        }
+        NodeKind::NDStr(_) | NodeKind::NDFunc(_) => {
+            generate_lval(ast, index, branch_num);
             return;
         }
         NodeKind::NDIf => {
@@ -87,43 +179,48 @@ pub fn generate_code(ast : &Ast, index : &usize, branch_num : &mut i32) {
             return;
         }
         NodeKind::NDFnCall(func) => {
-            if node.indices.len() <= 6 {
-                for i in 0..node.indices.len() {
-                    generate_code(ast, node.indices.get(i).unwrap(), branch_num);
-                }
-                for i in (0..node.indices.len()).rev() {
-                    match i {
-                        0 => println!("  pop rdi"),
-                        1 => println!("  pop rsi"),
-                        2 => println!("  pop rdx"),
-                        3 => println!("  pop rcx"),
-                        4 => println!("  pop r8"),
-                        5 => println!("  pop r9"),
-                        _ => std::process::exit(1),
-                    }
-                }
-
+            for i in 0..node.indices.len() {
+                generate_code(ast, node.indices.get(i).unwrap(), branch_num);
             }
+            pop_args(node.indices.len());
             println!("  call {}", func);
+            cast(&node.ty);
             println!("  push rax");
             return;
         }
-        NodeKind::NDFnDef(func_name, arg_names) => {
+        NodeKind::NDFnPtrCall => {
+            let (callee, args) = node.indices.split_first().unwrap();
+            for arg in args {
+                generate_code(ast, arg, branch_num);
+            }
+            generate_code(ast, callee, branch_num);
+            println!("  pop r10");
+            pop_args(args.len());
+            println!("  call r10");
+            cast(&node.ty);
+            println!("  push rax");
+            return;
+        }
+        NodeKind::NDFnDef(func_name, args, va_area) => {
             println!("{}:", func_name);
             println!("  push rbp");
             println!("  mov rbp, rsp");
             println!("  sub rsp, {}", ast.region); // lvar_num is a multiple of 16
-            for (i, arg) in arg_names.iter().enumerate() {
+            if let Some(area) = va_area.map(|var| &ast.vars[var]) { // 可変長引数の関数は、va_argで読めるように全ての引数レジスタを保存する
+                for (i, [reg64, ..]) in ARG_REGS.iter().enumerate() {
+                    println!("  mov [rbp - {}], {}", area.offset - 8 * i as i32, reg64);
+                }
+            }
+            for (i, arg) in args.iter().enumerate() {
                 println!("  mov rax, rbp");
-                println!("  sub rax, {}", ast.map.get(arg).unwrap().offset);
-                match i {
-                    0 => println!("  mov [rax], rdi"),
-                    1 => println!("  mov [rax], rsi"),
-                    2 => println!("  mov [rax], rdx"),
-                    3 => println!("  mov [rax], rcx"),
-                    4 => println!("  mov [rax], r8"),
-                    5 => println!("  mov [rax], r9"),
-                    _ => std::process::exit(1),
+                let var = &ast.vars[*arg];
+                println!("  sub rax, {}", var.offset);
+                let [reg64, reg32, reg16, reg8] = ARG_REGS[i];
+                match unqual(&var.ty) {
+                    Type::Int | Type::UInt | Type::Enum => println!("  mov [rax], {}", reg32),
+                    Type::Short | Type::UShort => println!("  mov [rax], {}", reg16),
+                    Type::Bool | Type::Char | Type::UChar => println!("  mov [rax], {}", reg8),
+                    _ => println!("  mov [rax], {}", reg64),
                 }
             }
             for i in 0..node.indices.len() {
@@ -135,6 +232,37 @@ pub fn generate_code(ast : &Ast, index : &usize, branch_num : &mut i32) {
             println!("  ret");
             return;
         }
+        NodeKind::NDVaStart(named, area) => { // va_listを、名前付きの引数の次の引数を指すように初期化する
+            generate_code(ast, node.indices.first().unwrap(), branch_num);
+            println!("  pop rax");
+            println!("  mov dword ptr [rax], {}", named * 8); // gp_offset
+            println!("  mov dword ptr [rax + 4], 176"); // fp_offset(浮動小数点数の引数には対応していない)
+            println!("  lea rdi, [rbp + 16]");
+            println!("  mov [rax + 8], rdi"); // overflow_arg_area
+            println!("  lea rdi, [rbp - {}]", ast.vars[*area].offset);
+            println!("  mov [rax + 16], rdi"); // reg_save_area
+            println!("  push rax");
+            return;
+        }
+        NodeKind::NDVaArg => { // 保存したレジスタの値を使い切ったら、スタックに積まれた引数を読む
+            generate_code(ast, node.indices.first().unwrap(), branch_num);
+            let use_num = *branch_num;
+            *branch_num += 1;
+            println!("  pop rdi");
+            println!("  mov eax, dword ptr [rdi]");
+            println!("  cmp eax, 48");
+            println!("  jae .Lvastack{}", use_num);
+            println!("  add rax, [rdi + 16]");
+            println!("  add dword ptr [rdi], 8");
+            println!("  jmp .Lvaend{}", use_num);
+            println!(".Lvastack{}:", use_num);
+            println!("  mov rax, [rdi + 8]");
+            println!("  add qword ptr [rdi + 8], 8");
+            println!(".Lvaend{}:", use_num);
+            load(&node.ty);
+            println!("  push rax");
+            return;
+        }
         NodeKind::NDAddr => {
             generate_lval(ast, node.indices.first().unwrap(), branch_num);
             return;
@@ -142,7 +270,7 @@ pub fn generate_code(ast : &Ast, index : &usize, branch_num : &mut i32) {
         NodeKind::NDDeref => {
             generate_code(ast, node.indices.first().unwrap(), branch_num);
             println!("  pop rax");
-            println!("  mov rax, [rax]");
+            load(&node.ty);
             println!("  push rax");
             return;
         }
@@ -154,6 +282,9 @@ pub fn generate_code(ast : &Ast, index : &usize, branch_num : &mut i32) {
 
     println!("  pop rdi\n  pop rax");
 
+    // 符号なし整数とポインタは符号なしで比較する
+    let operand_ty = &ast.tree[*node.indices.first().unwrap()].ty;
+    let unsigned_operands = is_unsigned(operand_ty) || matches!(operand_ty, Type::Ptr(_) | Type::Array(..));
     match node.kind {
         NodeKind::NDAdd => {
             println!("  add rax, rdi");
@@ -164,9 +295,23 @@ pub fn generate_code(ast : &Ast, index : &usize, branch_num : &mut i32) {
         NodeKind::NDMul => {
             println!("  imul rax, rdi");
         }
-        NodeKind::NDDiv => {
-            println!("  cqo");
-            println!("  idiv rdi");
+        NodeKind::NDDiv => match node.ty {
+            Type::Int => {
+                println!("  cdq");
+                println!("  idiv edi");
+            }
+            Type::UInt => {
+                println!("  xor edx, edx");
+                println!("  div edi");
+            }
+            Type::ULong => {
+                println!("  xor edx, edx");
+                println!("  div rdi");
+            }
+            _ => {
+                println!("  cqo");
+                println!("  idiv rdi");
+            }
         }
         NodeKind::NDEq => {
             println!("  cmp rax, rdi");
@@ -180,16 +325,20 @@ pub fn generate_code(ast : &Ast, index : &usize, branch_num : &mut i32) {
         }
         NodeKind::NDLeEq => {
             println!("  cmp rax, rdi");
-            println!("  setle al");
+            println!("  {} al", if unsigned_operands { "setbe" } else { "setle" });
             println!("  movzb rax, al");
         }
         NodeKind::NDLe => {
             println!("  cmp rax, rdi");
-            println!("  setl al");
+            println!("  {} al", if unsigned_operands { "setb" } else { "setl" });
             println!("  movzb rax, al");
         }
         _ => (),
     }
+    // intの演算結果は32ビットで桁あふれさせる
+    if matches!(node.kind, NodeKind::NDAdd | NodeKind::NDSub | NodeKind::NDMul | NodeKind::NDDiv) {
+        cast(&node.ty);
+    }
     println!("  push rax");
 }
 
@@ -197,14 +346,37 @@ pub fn generate_code(ast : &Ast, index : &usize, branch_num : &mut i32) {
 fn generate_lval(ast : &Ast, index : &usize, branch_num : &mut i32) {
     let node = &ast.tree[*index];
     match node.kind {
-        NodeKind::NDLVa(name) => {
+        NodeKind::NDLVa(var) => {
             println!("  mov rax, rbp");
-            println!("  sub rax, {}", ast.map.get(name).unwrap().offset);
+            println!("  sub rax, {}", ast.vars[var].offset);
             println!("  push rax");
         }
         NodeKind::NDDeref => {
             generate_code(ast, node.indices.first().unwrap(), branch_num);
         }
+        NodeKind::NDMember(offset, _) => {
+            generate_lval(ast, node.indices.first().unwrap(), branch_num);
+            println!("  pop rax");
+            println!("  add rax, {}", offset);
+            println!("  push rax");
+        }
+        NodeKind::NDStr(ref bytes) => { // 文字列リテラルは読み出し専用のデータ領域に置く
+            let label = *branch_num;
+            *branch_num += 1;
+            println!("  .pushsection .rodata");
+            println!(".LC{}:", label);
+            println!("  .byte {}", bytes.iter().map(|b| b.to_string()).collect::<Vec<_>>().join(", "));
+            println!("  .popsection");
+            println!("  lea rax, .LC{}[rip]", label);
+            println!("  push rax");
+        }
+        NodeKind::NDFunc(name) => { // 関数のアドレスはGOTから読む
+            println!("  mov rax, qword ptr [rip + {}@GOTPCREL]", name);
+            println!("  push rax");
+        }
+        _ if matches!(node.ty, Type::Struct(_)) => { // 構造体の値はそのアドレスで表される
+            generate_code(ast, index, branch_num);
+        }
         _ => {
             eprintln!("代入の左辺値が変数ではありません");
             std::process::exit(1);
@@ -1,31 +1,225 @@
-use std::{env, process};
+use std::{env, fs, io::Read, path::{Path, PathBuf}, process, time::Instant};
 mod tokenizer;
+mod preprocess;
 mod parser;
 mod codegen;
 mod ty;
 
+use tokenizer::SourceFile;
+
+// mycc自身の型に合わせた標準ヘッダ(stddef.hなど)を置くディレクトリの、実行ファイルのあるディレクトリからの相対パス
+// (実行ファイルと同じ場所、インストール先のlib/mycc、cargoのtarget/debugなどから見たリポジトリ)
+const BUILTIN_INCLUDE_DIRS : [&str; 3] = ["include", "../lib/mycc/include", "../../include"];
+
+// 標準のインクルードパス
+const SYSTEM_INCLUDE_PATHS : [&str; 3] = ["/usr/local/include", "/usr/include/x86_64-linux-gnu", "/usr/include"];
+
+// 組み込みのヘッダを置くディレクトリを探す。環境変数MYCC_INCLUDEがあればそれを使う
+// -Iの後、システムのヘッダより先に探す
+fn builtin_include_path() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("MYCC_INCLUDE") {
+        return Some(PathBuf::from(dir));
+    }
+    let exe = env::current_exe().ok()?.canonicalize().ok()?;
+    let exe_dir = exe.parent()?;
+    BUILTIN_INCLUDE_DIRS.iter().map(|dir| exe_dir.join(dir)).find(|dir| dir.join("stddef.h").is_file())
+}
+
+fn usage() -> ! {
+    eprintln!("引数の個数が正しくありません\n");
+    process::exit(1);
+}
+
+// 入力がファイル名ならその内容を、"-"なら標準入力を、それ以外はソースコードそのものとして読む
+fn read_input(input : &str) -> &'static SourceFile {
+    if input == "-" {
+        let mut contents = String::new();
+        if std::io::stdin().read_to_string(&mut contents).is_err() {
+            eprintln!("標準入力を読み込めません");
+            process::exit(1);
+        }
+        return SourceFile::new("<stdin>", contents);
+    }
+    let path = Path::new(input);
+    if path.is_file() || input.ends_with(".c") || input.ends_with(".h") {
+        match fs::read_to_string(path) {
+            Ok(contents) => SourceFile::new(input, contents),
+            Err(_) => {
+                eprintln!("{}を読み込めません", input);
+                process::exit(1);
+            }
+        }
+    }
+    else {
+        SourceFile::new("<command line>", input.to_string())
+    }
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() != 2 {
-        eprintln!("引数の個数が正しくありません\n");
-        process::exit(1);
+    let mut include_paths : Vec<PathBuf> = Vec::new();
+    let mut cmdline_macros : Vec<(bool, &str)> = Vec::new(); // (-Dか, 引数)
+    let mut preprocess_only = false;
+    let mut dump_macros = false;
+    let mut time_report = false;
+    let mut warn_unknown_pragmas = false;
+    let mut freestanding = false;
+    let mut deps_mode = None; // -M, -MM, -MD, -MMDのいずれか
+    let mut deps_file = None; // -MF
+    let mut deps_targets : Vec<String> = Vec::new(); // -MT
+    let mut deps_phony = false; // -MP
+    let mut input = None;
+    let mut i = 1;
+    while i < args.len() {
+        let arg = &args[i];
+        if arg == "-I" || arg == "-D" || arg == "-U" || arg == "-MF" || arg == "-MT" {
+            i += 1;
+            let value = args.get(i).unwrap_or_else(|| usage());
+            match arg.as_str() {
+                "-I" => include_paths.push(PathBuf::from(value)),
+                "-MF" => deps_file = Some(value.as_str()),
+                "-MT" => deps_targets.push(value.to_string()),
+                option => cmdline_macros.push((option == "-D", value)),
+            }
+        }
+        else if arg == "-M" || arg == "-MM" || arg == "-MD" || arg == "-MMD" {
+            deps_mode = Some(arg.as_str());
+        }
+        else if arg == "-MP" {
+            deps_phony = true;
+        }
+        else if let Some(file) = arg.strip_prefix("-MF") {
+            deps_file = Some(file);
+        }
+        else if let Some(target) = arg.strip_prefix("-MT") {
+            deps_targets.push(target.to_string());
+        }
+        else if let Some(dir) = arg.strip_prefix("-I") {
+            include_paths.push(PathBuf::from(dir));
+        }
+        else if let Some(def) = arg.strip_prefix("-D") {
+            cmdline_macros.push((true, def));
+        }
+        else if let Some(name) = arg.strip_prefix("-U") {
+            cmdline_macros.push((false, name));
+        }
+        else if arg == "-E" {
+            preprocess_only = true;
+        }
+        else if arg == "-dM" {
+            dump_macros = true;
+        }
+        else if arg == "-ftime-report" {
+            time_report = true;
+        }
+        else if arg == "-ffreestanding" {
+            freestanding = true;
+        }
+        else if arg == "-Wunknown-pragmas" || arg == "-Wno-unknown-pragmas" {
+            warn_unknown_pragmas = arg == "-Wunknown-pragmas";
+        }
+        else if input.is_none() {
+            input = Some(arg.as_str());
+        }
+        else {
+            usage();
+        }
+        i += 1;
+    }
+    let mut system_include_paths : Vec<PathBuf> = builtin_include_path().into_iter().collect();
+    system_include_paths.extend(SYSTEM_INCLUDE_PATHS.iter().map(PathBuf::from));
+
+    let file = read_input(input.unwrap_or_else(|| usage()));
+    let start = Instant::now();
+    let tokens = tokenizer::Token::tokenize(file);
+    let mut preprocessor = preprocess::Preprocessor::new(include_paths, system_include_paths);
+    preprocessor.warn_unknown_pragmas = warn_unknown_pragmas;
+    if freestanding {
+        preprocessor.define_macro("__STDC_HOSTED__", "0");
+    }
+    for (define, arg) in cmdline_macros {
+        if !define {
+            preprocessor.undef_macro(arg);
+        }
+        else {
+            // -DNAMEは"#define NAME 1"、-DNAME=valueは"#define NAME value"
+            match arg.split_once('=') {
+                Some((name, value)) => preprocessor.define_macro(name, value),
+                None => preprocessor.define_macro(arg, "1"),
+            }
+        }
     }
-    
-    let expression = &args[1];
-    let tokens = tokenizer::Token::tokenize(expression);
+    let mut tokens = preprocessor.preprocess(tokens);
+    let preprocess_time = start.elapsed();
+    if time_report {
+        let stats = &preprocessor.stats;
+        eprintln!("プリプロセス: {:.6}秒", preprocess_time.as_secs_f64());
+        eprintln!("  読み込んだファイル: {} ({}バイト)", stats.files_read, stats.bytes_read);
+        eprintln!("  #pragma onceで省略したインクルード: {}", stats.skipped_by_once);
+        eprintln!("  インクルードガードで省略したインクルード: {} ({}バイト)", stats.skipped_by_guard, stats.bytes_skipped);
+    }
+    if let Some(mode) = deps_mode {
+        // ソースファイル名の拡張子を.oに替えたものを既定のターゲットとする
+        let is_file = !file.name.starts_with('<');
+        let stem = if is_file { Path::new(&file.name).file_stem().unwrap().to_string_lossy().into_owned() } else { "-".to_string() };
+        if deps_targets.is_empty() {
+            deps_targets.push(format!("{}.o", stem).replace(' ', "\\ "));
+        }
+        let deps = preprocessor.dependencies(mode == "-M" || mode == "-MD");
+        let rules = preprocess::dependency_rules(&deps_targets, if is_file { Some(&file.name) } else { None }, &deps, deps_phony);
+        // -Mと-MMは規則だけを出力し、-MDと-MMDはコンパイルを続ける
+        let only_deps = mode == "-M" || mode == "-MM";
+        let deps_file = match deps_file {
+            Some(path) => Some(path.to_string()),
+            None if only_deps => None,
+            None => Some(format!("{}.d", stem)),
+        };
+        match deps_file {
+            Some(path) => {
+                if fs::write(&path, rules).is_err() {
+                    eprintln!("{}に書き込めません", path);
+                    process::exit(1);
+                }
+            }
+            None => print!("{}", rules),
+        }
+        if only_deps {
+            return;
+        }
+    }
+    if preprocess_only {
+        if dump_macros {
+            for def in preprocessor.macro_definitions(tokens.last().unwrap()) {
+                println!("{}", def);
+            }
+        }
+        else {
+            preprocess::print_tokens(&tokens);
+        }
+        return;
+    }
+    tokenizer::Token::convert_keywords(&mut tokens);
     // println!("{:?}", tokens);
-    let asts = parser::Node::parse(expression, &tokens);
+    let start = Instant::now();
+    let asts = parser::Node::parse(&file.contents, &tokens);
     // println!("{:?}", asts);
+    if time_report {
+        eprintln!("構文解析: {:.6}秒", start.elapsed().as_secs_f64());
+    }
 
     println!(".intel_syntax noprefix");
     println!(".globl main");
 
+    let start = Instant::now();
     let mut branch_num = 0;
     for ast in asts {
         let index = ast.tree.len() - 1;
         codegen::generate_code(&ast, &index, &mut branch_num);
         // println!("  pop rax"); // pop return value of the previously executed function
     }
+    if time_report {
+        eprintln!("コード生成: {:.6}秒", start.elapsed().as_secs_f64());
+    }
 
 }
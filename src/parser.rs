@@ -1,9 +1,24 @@
-use crate::tokenizer::{Token, TokenKind};
-use std::{collections::HashMap, vec};
-use crate::ty::{Type, type_of_node, type_to_size};
+use crate::tokenizer::{Token, TokenKind, read_escaped_char};
+use std::{cell::RefCell, collections::HashMap, rc::Rc, vec};
+use crate::ty::{Type, FuncType, Member, StructInfo, Qualifiers, type_of_node};
 use crate::ty;
 
+// 型指定子になる予約語
+const TYPE_SPECIFIERS : [&str; 8] = ["void", "_Bool", "int", "char", "short", "long", "signed", "unsigned"];
+// 型修飾子になる予約語
+const TYPE_QUALIFIERS : [&str; 3] = ["const", "volatile", "restrict"];
+
+// __has_attribute, __has_builtinで報告する、構文解析器が理解する属性と組み込み関数
+pub const SUPPORTED_ATTRIBUTES : [&str; 2] = ["packed", "aligned"];
+pub const SUPPORTED_BUILTINS : [&str; 5] = [
+    "__builtin_expect", "__builtin_va_start", "__builtin_va_arg", "__builtin_va_copy", "__builtin_va_end",
+];
+
+// 関数の引数の並び(名前のトークンの位置と型)
+type Params = Vec<(Option<usize>, Type)>;
+
 #[derive(PartialEq, Eq, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum NodeKind<'a> {
     NDAdd, 
     NDSub, 
@@ -13,7 +28,7 @@ pub enum NodeKind<'a> {
     NDLeEq,
     NDEq,
     NDAs, // assign
-    NDLVa(&'a str), // local variable(offset from RBP)
+    NDLVa(usize), // local variable(varsの何番目か)
     NDNEq,
     NDRet,
     NDIf, 
@@ -21,10 +36,17 @@ pub enum NodeKind<'a> {
     NDFor,
     NDBlock, // code block
     NDFnCall(&'a str),
-    NDFnDef(&'a str, Vec<&'a str>), // (func name, argument lists)
+    NDFnPtrCall, // 関数ポインタによる呼び出し(最初の子が関数のアドレス、残りが引数)
+    NDFunc(&'a str), // 式の中の関数名(関数のアドレスを表す)
+    NDVaStart(usize, usize), // va_start(名前付きの引数の個数, 引数レジスタを保存した変数)
+    NDVaArg, // va_arg(ノードの型の値を取り出す)
+    NDFnDef(&'a str, Vec<usize>, Option<usize>), // (func name, argument lists, 可変長引数の関数で引数レジスタを保存する変数)
     NDAddr, 
     NDDeref,
-    NDNum(i32),
+    NDNum(i64),
+    NDCast, // 子の値をこのノードの型に変換する
+    NDStr(Vec<u8>), // 文字列リテラル(終端の0を含む)
+    NDMember(i32, Qualifiers), // 構造体のメンバ(構造体の先頭からのオフセット, メンバの型修飾子)
 }
 
 #[derive(Debug)]
@@ -37,7 +59,7 @@ pub struct Node<'a> {
 #[derive(Debug)]
 pub struct Ast<'a> {
     pub tree : Vec<Node<'a>>,
-    pub map : HashMap<&'a str, VarInfo>,
+    pub vars : Vec<VarInfo>, // 関数の変数(NDLVaが指す)
     pub region : i32
 }
 
@@ -47,6 +69,87 @@ pub struct VarInfo {
     pub offset : i32
 }
 
+// 通常の識別子が表すもの
+#[derive(Debug)]
+enum Ident {
+    Var(usize), // 変数(型とオフセットはScopeのvarsの何番目かに記録する)
+    EnumConst(i64), // 列挙子
+    Typedef(Type), // typedefで宣言された型の名前
+    Func { ty : Type, decl : usize, defined : bool }, // 関数(declは定義、なければ最後の宣言の位置のトークン)
+}
+
+// 識別子やタグのように、宣言されたブロックの中だけで有効な名前
+#[derive(Debug)]
+pub struct Scope<'a> {
+    names : Vec<HashMap<&'a str, Ident>>, // 外側のブロックから順に並べる
+    tags : Vec<HashMap<&'a str, Type>>,
+    struct_count : usize, // これまでに定義した構造体の数
+    vars : Vec<VarInfo>, // 解析中の関数で宣言した変数(同じ名前でも宣言ごとに別の変数になる)
+}
+
+impl<'a> Scope<'a> {
+    fn new() -> Scope<'a> {
+        Scope { names : vec![HashMap::new()], tags : vec![HashMap::new()], struct_count : 0, vars : Vec::new() }
+    }
+
+    fn enter(&mut self) {
+        self.names.push(HashMap::new());
+        self.tags.push(HashMap::new());
+    }
+
+    fn leave(&mut self) {
+        self.names.pop();
+        self.tags.pop();
+    }
+
+    // 内側のブロックから順に識別子を探す
+    fn find_name(&self, name : &str) -> Option<&Ident> {
+        self.names.iter().rev().find_map(|names| names.get(name))
+    }
+
+    // 現在のブロックで宣言された識別子を探す
+    fn find_local_name(&self, name : &str) -> Option<&Ident> {
+        self.names.last().unwrap().get(name)
+    }
+
+    fn push_name(&mut self, name : &'a str, ident : Ident) {
+        self.names.last_mut().unwrap().insert(name, ident);
+    }
+
+    // ファイルのスコープに識別子を宣言する
+    fn push_global_name(&mut self, name : &'a str, ident : Ident) {
+        self.names.first_mut().unwrap().insert(name, ident);
+    }
+
+    // 変数を追加し、varsでの位置を返す(nameがなければ名前で参照されない変数になる)
+    fn push_var(&mut self, name : Option<&'a str>, info : VarInfo) -> usize {
+        self.vars.push(info);
+        if let Some(name) = name {
+            self.push_name(name, Ident::Var(self.vars.len() - 1));
+        }
+        self.vars.len() - 1
+    }
+
+    // 内側のブロックから順にタグを探す
+    fn find_tag(&self, tag : &str) -> Option<&Type> {
+        self.tags.iter().rev().find_map(|tags| tags.get(tag))
+    }
+
+    // 現在のブロックで宣言されたタグを探す
+    fn find_local_tag(&self, tag : &str) -> Option<&Type> {
+        self.tags.last().unwrap().get(tag)
+    }
+
+    fn push_tag(&mut self, tag : &'a str, ty : Type) {
+        self.tags.last_mut().unwrap().insert(tag, ty);
+    }
+
+    fn new_struct(&mut self, is_union : bool) -> Rc<RefCell<StructInfo>> {
+        self.struct_count += 1;
+        Rc::new(RefCell::new(StructInfo::new(self.struct_count, is_union)))
+    }
+}
+
 
 impl<'a> Node<'a> {
     fn new(kind: NodeKind<'a>, indices : Vec<usize>, ty : Type) -> Node<'a> {
@@ -59,248 +162,839 @@ impl<'a> Node<'a> {
     fn new_init(kind: NodeKind<'a>, indices : Vec<usize>) -> Node<'a> {
         Node::new(kind, indices, Type::Init)
     }
-    fn new_num(val : i32, ty : Type) -> Node<'a> {
+    fn new_num(val : i64, ty : Type) -> Node<'a> {
         Node::new(NodeKind::NDNum(val), Vec::new(), ty)
     }
-    fn new_lvar(name : &'a str, ty : Type) -> Node<'a> {
-        Node::new(NodeKind::NDLVa(name), Vec::new(), ty)
+    fn new_lvar(var : usize, ty : &Type) -> Node<'a> {
+        Node::new(NodeKind::NDLVa(var), Vec::new(), ty::unqual(ty).clone())
     }
     fn new_ret(left_index : usize) -> Node<'a> {
         let vec = vec![left_index];
         Node::new_init(NodeKind::NDRet, vec)
     }
-    fn new_add(tree : &mut Vec<Node<'a>>, lval_index : usize, rval_index : usize, vec : Vec<usize>) -> Node<'a> {
+    // ポインタに整数を足し引きするときは、整数を指す先の型の大きさ倍する
+    fn new_scaled(tree : &mut Vec<Node<'a>>, int_index : usize, pointee : &Type) -> usize {
+        tree.push(Node::new_num(ty::type_to_size(pointee) as i64, Type::Long));
+        tree.push(Node::new(NodeKind::NDMul, vec![int_index, tree.len() - 1], Type::Long));
+        tree.len() - 1
+    }
+
+    fn new_add(tree : &mut Vec<Node<'a>>, lval_index : usize, rval_index : usize, token : &Token) -> Node<'a> {
         ty::type_of_node(tree, lval_index);
         ty::type_of_node(tree, rval_index);
 
-        match (tree[lval_index].ty.clone(), tree[rval_index].ty.clone()) {
-            (Type::Int, Type::Int) => Node::new(NodeKind::NDAdd, vec, Type::Int),
-            (Type::Ptr(_), Type::Ptr(_)) => {
-                eprintln!("ポインタ同士を足しています");
-                std::process::exit(1);
-            }
-            (Type::Ptr(ty1), Type::Int) => {
-                tree.push(Node::new_num(8, Type::Int));
-                tree.push(Node::new(NodeKind::NDMul, vec![rval_index, tree.len() - 1], Type::Int));
-                Node::new(NodeKind::NDAdd, vec![lval_index, tree.len() - 1], Type::Ptr(ty1.clone()).clone())
+        match (ty::decay(&tree[lval_index].ty), ty::decay(&tree[rval_index].ty)) {
+            (lhs, rhs) if ty::is_integer(&lhs) && ty::is_integer(&rhs) => {
+                let ty = ty::common_type(&lhs, &rhs);
+                let (lval_index, rval_index) = (ty::cast_to(tree, lval_index, &ty), ty::cast_to(tree, rval_index, &ty));
+                Node::new(NodeKind::NDAdd, vec![lval_index, rval_index], ty)
             }
-            (Type::Int, Type::Ptr(ty1)) => {
-                tree.push(Node::new_num(8, Type::Int));
-                tree.push(Node::new(NodeKind::NDMul, vec![lval_index, tree.len() - 1], Type::Int));
-                Node::new(NodeKind::NDAdd, vec![rval_index, tree.len() - 1], Type::Ptr(ty1.clone()).clone())
+            (Type::Ptr(_), Type::Ptr(_)) => Token::error_at(token, "ポインタ同士を足しています"),
+            (Type::Ptr(ty1), rhs) if ty::is_integer(&rhs) => {
+                let scaled = Node::new_scaled(tree, rval_index, &ty1);
+                Node::new(NodeKind::NDAdd, vec![lval_index, scaled], Type::Ptr(ty1))
             }
-            (_, _) => {
-                eprintln!("違法な足し算です");
-                std::process::exit(1);
+            (lhs, Type::Ptr(ty1)) if ty::is_integer(&lhs) => {
+                let scaled = Node::new_scaled(tree, lval_index, &ty1);
+                Node::new(NodeKind::NDAdd, vec![rval_index, scaled], Type::Ptr(ty1))
             }
+            (_, _) => Token::error_at(token, "違法な足し算です"),
         }
 
     }
 
-    fn new_sub(tree : &mut Vec<Node<'a>>, lval_index : usize, rval_index : usize, vec : Vec<usize>) -> Node<'a> {
+    fn new_sub(tree : &mut Vec<Node<'a>>, lval_index : usize, rval_index : usize, token : &Token) -> Node<'a> {
         ty::type_of_node(tree, lval_index);
         ty::type_of_node(tree, rval_index);
 
-        match (tree[lval_index].ty.clone(), tree[rval_index].ty.clone()) {
-            (Type::Int, Type::Int) => Node::new(NodeKind::NDSub, vec, Type::Int),
-            (Type::Ptr(_), Type::Ptr(_)) => {
-                tree.push(Node::new(NodeKind::NDSub, vec, Type::Int));
+        match (ty::decay(&tree[lval_index].ty), ty::decay(&tree[rval_index].ty)) {
+            (lhs, rhs) if ty::is_integer(&lhs) && ty::is_integer(&rhs) => {
+                let ty = ty::common_type(&lhs, &rhs);
+                let (lval_index, rval_index) = (ty::cast_to(tree, lval_index, &ty), ty::cast_to(tree, rval_index, &ty));
+                Node::new(NodeKind::NDSub, vec![lval_index, rval_index], ty)
+            }
+            (Type::Ptr(ty1), Type::Ptr(ty2)) => { // ポインタの差は要素の個数で、ptrdiff_tと同じlong
+                if ty::unqual(&ty1) != ty::unqual(&ty2) {
+                    Token::error_at(token, "異なる型を指すポインタ同士を引いています");
+                }
+                tree.push(Node::new(NodeKind::NDSub, vec![lval_index, rval_index], Type::Long));
                 let tmp = tree.len() - 1;
-                tree.push(Node::new_num(8, Type::Int));
-                Node::new(NodeKind::NDDiv, vec![tmp, tree.len() - 1], Type::Int)
+                tree.push(Node::new_num(ty::type_to_size(&ty1) as i64, Type::Long));
+                Node::new(NodeKind::NDDiv, vec![tmp, tree.len() - 1], Type::Long)
             }
-            (Type::Ptr(ty1), Type::Int) => {
-                tree.push(Node::new_num(8, Type::Int));
-                tree.push(Node::new(NodeKind::NDMul, vec![rval_index, tree.len() - 1], Type::Int));
-                Node::new(NodeKind::NDSub, vec![lval_index, tree.len() - 1], Type::Ptr(ty1.clone()).clone())
+            (Type::Ptr(ty1), rhs) if ty::is_integer(&rhs) => {
+                let scaled = Node::new_scaled(tree, rval_index, &ty1);
+                Node::new(NodeKind::NDSub, vec![lval_index, scaled], Type::Ptr(ty1))
             }
-            (Type::Int, Type::Ptr(ty1)) => {
-                tree.push(Node::new_num(8, Type::Int));
-                tree.push(Node::new(NodeKind::NDMul, vec![lval_index, tree.len() - 1], Type::Int));
-                Node::new(NodeKind::NDSub, vec![lval_index, tree.len() - 1], Type::Ptr(ty1.clone()).clone())
+            (lhs, Type::Ptr(_)) if ty::is_integer(&lhs) => Token::error_at(token, "整数からポインタを引くことはできません"),
+            (_, _) => Token::error_at(token, "違法な引き算です"),
+        }
+
+    }
+
+    // 型tyの変数を置く場所を確保し、rbpからのオフセットを返す(alignは_Alignasで指定されたアラインメント)
+    fn new_offset(region : &mut i32, ty : &Type, align : i32) -> i32 {
+        *region = ty::align_to(*region + ty::type_to_size(ty), ty::type_to_align(ty).max(align));
+        *region
+    }
+
+    // 型の名前の始まりか(識別子はtypedefされた名前なら型の名前になる)
+    fn is_type(token : &Token, scope : &Scope) -> bool {
+        if let TokenKind::TKIdent(name) = token.kind {
+            return matches!(scope.find_name(name), Some(Ident::Typedef(_)));
+        }
+        TYPE_SPECIFIERS.iter().chain(&TYPE_QUALIFIERS).any(|spec| token.is(spec))
+            || ["struct", "union", "enum", "_Noreturn", "_Alignas"].iter().any(|kw| token.is(kw))
+    }
+
+    // 宣言子の前の*を読み、tyを指すポインタ型にする(*の後の型修飾子はポインタ自身を修飾する)
+    fn pointers(s : &str, tokens : &'a [Token], index : &mut usize, mut ty : Type) -> Type {
+        while Token::consume(s, &tokens[*index], index, "*") {
+            let mut quals = Qualifiers::default();
+            Node::type_qualifiers(s, tokens, index, &mut quals);
+            ty = ty::qualify(Type::Ptr(Box::new(ty)), quals);
+        }
+        ty
+    }
+
+    // 型修飾子の並びを読み、qualsに加える
+    // 関数指定子_Noreturnも型修飾子と同じ位置に書けるが、型には影響しないので読み飛ばす
+    fn type_qualifiers(s : &str, tokens : &'a [Token], index : &mut usize, quals : &mut Qualifiers) {
+        loop {
+            if Token::consume(s, &tokens[*index], index, "_Noreturn") {
+                continue;
             }
-            (_, _) => {
-                eprintln!("違法な引き算です");
-                std::process::exit(1);
+            if Token::consume(s, &tokens[*index], index, "const") {
+                quals.is_const = true;
+            }
+            else if Token::consume(s, &tokens[*index], index, "volatile") {
+                quals.is_volatile = true;
+            }
+            else if Token::consume(s, &tokens[*index], index, "restrict") {
+                quals.is_restrict = true;
+            }
+            else {
+                return;
             }
         }
+    }
 
+    // _Alignas(型名)または_Alignas(定数式)の並びを読み、指定された最大のアラインメントを返す(指定がなければ0)
+    fn alignas(s : &str, tokens : &'a [Token], index : &mut usize, scope : &mut Scope<'a>) -> i32 {
+        let mut align = 0;
+        while Token::consume(s, &tokens[*index], index, "_Alignas") {
+            Token::expect(s, &tokens[*index], index, "(");
+            let token = &tokens[*index];
+            let val = if Node::is_type(token, scope) {
+                ty::type_to_align(&Node::type_name(s, tokens, index, scope)) as i64
+            }
+            else {
+                Node::const_expr(s, tokens, index, scope)
+            };
+            if val < 0 || val & (val - 1) != 0 { // 0は指定がないのと同じ
+                Token::error_at(token, "アラインメントは2の累乗にしてください");
+            }
+            Token::expect(s, &tokens[*index], index, ")");
+            align = align.max(val as i32);
+        }
+        align
     }
 
-    fn find_type(s : &str, tokens : &'a Vec<Token>, index : &mut usize) -> Type {
-        let mut func_type = Type::Init;
+    // 型修飾子と型指定子の並びを読み、その型を返す
+    fn base_type(s : &str, tokens : &'a [Token], index : &mut usize, scope : &mut Scope<'a>) -> Type {
         let token = &tokens[*index];
-        if Token::consume(s, token, index, "int") {
-            func_type = Type::Int;
+        let mut quals = Qualifiers::default();
+        Node::type_qualifiers(s, tokens, index, &mut quals);
+        let ty = Node::type_specifiers(s, tokens, index, scope, &mut quals);
+        Node::type_qualifiers(s, tokens, index, &mut quals);
+        if quals.is_restrict && !matches!(ty::unqual(&ty), Type::Ptr(_)) {
+            Token::error_at(token, "restrictはポインタ型にしか使えません");
         }
-        else {
-            Token::error_msg(s, token.index, "正しい型を使用してください")
+        ty::qualify(ty, quals)
+    }
+
+    // 型指定子の並びを読み、その型を返す(間に書かれた型修飾子はqualsに加える)
+    fn type_specifiers(s : &str, tokens : &'a [Token], index : &mut usize, scope : &mut Scope<'a>, quals : &mut Qualifiers) -> Type {
+        if let TokenKind::TKIdent(name) = tokens[*index].kind {
+            if let Some(Ident::Typedef(ty)) = scope.find_name(name) {
+                *index += 1;
+                return ty.clone();
+            }
+        }
+        if Token::consume(s, &tokens[*index], index, "struct") {
+            return Node::struct_decl(s, tokens, index, scope, false);
+        }
+        if Token::consume(s, &tokens[*index], index, "union") {
+            return Node::struct_decl(s, tokens, index, scope, true);
         }
-        func_type
+        if Token::consume(s, &tokens[*index], index, "enum") {
+            return Node::enum_decl(s, tokens, index, scope);
+        }
+        // 型指定子は任意の順序で並べられる
+        let token = &tokens[*index];
+        let mut specs : Vec<&str> = Vec::new();
+        loop {
+            Node::type_qualifiers(s, tokens, index, quals);
+            match TYPE_SPECIFIERS.iter().find(|spec| tokens[*index].is(spec)) {
+                Some(spec) => specs.push(spec),
+                None => break,
+            }
+            *index += 1;
+        }
+        specs.sort_unstable();
+        match specs.as_slice() {
+            ["void"] => Type::Void,
+            ["_Bool"] => Type::Bool,
+            ["int"] | ["signed"] | ["int", "signed"] => Type::Int,
+            ["unsigned"] | ["int", "unsigned"] => Type::UInt,
+            ["char"] | ["char", "signed"] => Type::Char,
+            ["char", "unsigned"] => Type::UChar,
+            ["short"] | ["int", "short"] | ["short", "signed"] | ["int", "short", "signed"] => Type::Short,
+            ["short", "unsigned"] | ["int", "short", "unsigned"] => Type::UShort,
+            ["long"] | ["int", "long"] | ["long", "signed"] | ["int", "long", "signed"]
+                | ["long", "long"] | ["int", "long", "long"] | ["long", "long", "signed"] | ["int", "long", "long", "signed"] => Type::Long,
+            ["long", "unsigned"] | ["int", "long", "unsigned"] | ["long", "long", "unsigned"] | ["int", "long", "long", "unsigned"] => Type::ULong,
+            _ => Token::error_at(token, "正しい型を使用してください"),
+        }
+    }
+
+    // structまたはunionに続くタグとメンバの宣言を読み、構造体型を返す
+    fn struct_decl(s : &str, tokens : &'a [Token], index : &mut usize, scope : &mut Scope<'a>, is_union : bool) -> Type {
+        let start = *index - 1;
+        let (mut packed, mut aligned) = Node::attributes(s, tokens, index, scope);
+        let tag = match tokens[*index].kind {
+            TokenKind::TKIdent(tag) => {
+                *index += 1;
+                Some(tag)
+            }
+            _ => None,
+        };
+        if !Token::consume(s, &tokens[*index], index, "{") { // 定義済みの構造体か、前方宣言
+            let tag = tag.unwrap_or_else(|| Token::error_at(&tokens[*index], "構造体のタグがありません"));
+            if let Some(ty) = scope.find_tag(tag) {
+                Node::check_tag_kind(&tokens[*index - 1], ty, if is_union { "union" } else { "struct" });
+                return ty.clone();
+            }
+            let ty = Type::Struct(scope.new_struct(is_union));
+            scope.push_tag(tag, ty.clone());
+            return ty;
+        }
+
+        // 同じブロックで前方宣言された構造体なら、その型を完成させる
+        let info = match tag.map(|tag| (tag, scope.find_local_tag(tag).cloned())) {
+            Some((_, Some(ty))) => {
+                Node::check_tag_kind(&tokens[*index - 2], &ty, if is_union { "union" } else { "struct" });
+                match ty {
+                    Type::Struct(info) if !info.borrow().complete => info,
+                    _ => Token::error_at(&tokens[*index - 2], "構造体が再定義されています"),
+                }
+            }
+            Some((tag, None)) => {
+                let info = scope.new_struct(is_union);
+                scope.push_tag(tag, Type::Struct(info.clone()));
+                info
+            }
+            None => scope.new_struct(is_union),
+        };
+
+        let mut members : Vec<Member> = Vec::new();
+        while !Node::consume_block_end(s, tokens, index) {
+            let align = Node::alignas(s, tokens, index, scope);
+            let base = Node::base_type(s, tokens, index, scope);
+            loop {
+                let (ty, name) = Node::declarator(s, tokens, index, scope, base.clone());
+                let token = &tokens[name.unwrap_or_else(|| Token::error_at(&tokens[*index], "メンバ名ではありません"))];
+                let name = token.text();
+                if *ty::unqual(&ty) == Type::Void || ty::is_incomplete(&ty) {
+                    Token::error_at(token, "不完全な型のメンバは宣言できません");
+                }
+                if let Type::Func(_) = ty {
+                    Token::error_at(token, "関数型のメンバは宣言できません");
+                }
+                if members.iter().any(|member| member.name == name) {
+                    Token::error_at(token, "同じ名前のメンバが宣言されています");
+                }
+                members.push(Member { name : name.to_string(), ty, offset : 0, align });
+                if !Token::consume(s, &tokens[*index], index, ",") {
+                    break;
+                }
+            }
+            Token::expect(s, &tokens[*index], index, ";");
+        }
+        let (packed_after, aligned_after) = Node::attributes(s, tokens, index, scope);
+        packed |= packed_after;
+        aligned = aligned.max(aligned_after);
+
+        // 構造体の定義より前にある、最後の#pragma packに従う
+        let pack = tokens[..start].iter().rev().find_map(|token| match token.kind {
+            TokenKind::TKPack(pack) => Some(pack as i32),
+            _ => None,
+        }).unwrap_or(0);
+        let mut struct_info = info.borrow_mut();
+        struct_info.members = members;
+        struct_info.layout(if packed { 1 } else { pack }, aligned);
+        drop(struct_info);
+        Type::Struct(info)
+    }
+
+    // typedefに続く型と名前の並びを読み、型の別名を宣言する
+    fn typedef_decl(s : &str, tokens : &'a [Token], index : &mut usize, scope : &mut Scope<'a>) {
+        let base = Node::base_type(s, tokens, index, scope);
+        loop {
+            let (ty, name) = Node::declarator(s, tokens, index, scope, base.clone());
+            let token = &tokens[name.unwrap_or_else(|| Token::error_at(&tokens[*index], "型の名前ではありません"))];
+            let name = token.text();
+            match scope.find_local_name(name) {
+                Some(Ident::Typedef(prev)) if *prev == ty => (), // 同じ型への再定義は許される
+                Some(_) => Token::error_at(token, "既に宣言された名前です"),
+                None => scope.push_name(name, Ident::Typedef(ty)),
+            }
+            if !Token::consume(s, &tokens[*index], index, ",") {
+                break;
+            }
+        }
+    }
+
+    // struct, union, enumで同じタグを使っていないか調べる
+    fn check_tag_kind(token : &Token, ty : &Type, kind : &str) {
+        let tag_kind = match ty {
+            Type::Struct(info) if info.borrow().is_union => "union",
+            Type::Struct(_) => "struct",
+            _ => "enum",
+        };
+        if tag_kind != kind {
+            Token::error_at(token, "異なる種類の型で同じタグが使われています");
+        }
+    }
+
+    // enumに続くタグと列挙子の宣言を読み、列挙型を返す
+    fn enum_decl(s : &str, tokens : &'a [Token], index : &mut usize, scope : &mut Scope<'a>) -> Type {
+        let tag = match tokens[*index].kind {
+            TokenKind::TKIdent(tag) => {
+                *index += 1;
+                Some(tag)
+            }
+            _ => None,
+        };
+        if !Token::consume(s, &tokens[*index], index, "{") { // 定義済みの列挙型
+            let tag = tag.unwrap_or_else(|| Token::error_at(&tokens[*index], "列挙型のタグがありません"));
+            match scope.find_tag(tag) {
+                Some(ty) => Node::check_tag_kind(&tokens[*index - 1], ty, "enum"),
+                None => Token::error_at(&tokens[*index - 1], "定義されていない列挙型です"),
+            }
+            return Type::Enum;
+        }
+        if let Some(tag) = tag {
+            if scope.find_local_tag(tag).is_some() {
+                Token::error_at(&tokens[*index - 2], "列挙型が再定義されています");
+            }
+            scope.push_tag(tag, Type::Enum);
+        }
+
+        // 値を指定しない列挙子は、直前の列挙子の値に1を足した値になる
+        let mut val = 0;
+        loop {
+            Node::skip_pragmas(tokens, index);
+            let token = &tokens[*index];
+            let name = match token.kind {
+                TokenKind::TKIdent(name) => name,
+                _ => Token::error_at(token, "列挙子ではありません"),
+            };
+            *index += 1;
+            if scope.find_local_name(name).is_some() {
+                Token::error_at(token, "既に宣言された名前です");
+            }
+            if Token::consume(s, &tokens[*index], index, "=") {
+                val = Node::const_expr(s, tokens, index, scope);
+            }
+            if !(i32::MIN as i64..=i32::MAX as i64).contains(&val) {
+                Token::error_at(token, "列挙子の値がintの範囲を超えています");
+            }
+            scope.push_name(name, Ident::EnumConst(val));
+            val += 1;
+            if !Token::consume(s, &tokens[*index], index, ",") {
+                if !Node::consume_block_end(s, tokens, index) {
+                    Token::expect(s, &tokens[*index], index, "}");
+                }
+                break;
+            }
+            if Node::consume_block_end(s, tokens, index) { // 最後のカンマは省略できる
+                break;
+            }
+        }
+        Type::Enum
+    }
+
+    // 整数定数式を読み、その値を返す
+    fn const_expr(s : &str, tokens : &'a [Token], index : &mut usize, scope : &mut Scope<'a>) -> i64 {
+        let token = &tokens[*index];
+        let mut tree : Vec<Node> = Vec::new();
+        let mut region = 0;
+        let expr = Node::equality(s, tokens, index, &mut tree, &mut region, scope);
+        type_of_node(&mut tree, expr);
+        Node::eval(&tree, expr, token)
+    }
+
+    // 定数式の木を評価する
+    fn eval(tree : &[Node], index : usize, token : &Token) -> i64 {
+        let node = &tree[index];
+        let operand = |k : usize| Node::eval(tree, node.indices[k], token);
+        let val = match node.kind {
+            NodeKind::NDNum(val) => val,
+            NodeKind::NDCast => operand(0),
+            NodeKind::NDAdd => operand(0).wrapping_add(operand(1)),
+            NodeKind::NDSub => operand(0).wrapping_sub(operand(1)),
+            NodeKind::NDMul => operand(0).wrapping_mul(operand(1)),
+            NodeKind::NDDiv => {
+                let (lhs, rhs) = (operand(0), operand(1));
+                if rhs == 0 {
+                    Token::error_at(token, "定数式の中で0で割っています");
+                }
+                if ty::is_unsigned(&node.ty) { ((lhs as u64) / (rhs as u64)) as i64 } else { lhs.wrapping_div(rhs) }
+            }
+            NodeKind::NDEq => (operand(0) == operand(1)) as i64,
+            NodeKind::NDNEq => (operand(0) != operand(1)) as i64,
+            NodeKind::NDLe | NodeKind::NDLeEq => {
+                let (lhs, rhs) = (operand(0), operand(1));
+                let ordering = if ty::is_unsigned(&tree[node.indices[0]].ty) { (lhs as u64).cmp(&(rhs as u64)) } else { lhs.cmp(&rhs) };
+                match node.kind {
+                    NodeKind::NDLe => ordering.is_lt() as i64,
+                    _ => ordering.is_le() as i64,
+                }
+            }
+            _ => Token::error_at(token, "定数式ではありません"),
+        };
+        ty::cast_value(val, &node.ty)
     }
 
-    fn init_map(map : &mut HashMap<&'a str, VarInfo>, code : &Vec<Ast<'a>>) {
-        for ast in code.iter() {
-            let node = &ast.tree.last().unwrap();
-            match node.kind {
-                NodeKind::NDFnDef(func_name, _) => {
-                    map.insert(func_name.clone(), VarInfo { ty: node.ty.clone(), offset: -1});
+    // __attribute__((packed))と__attribute__((aligned(N)))を読み、(packed, aligned)を返す
+    fn attributes(s : &str, tokens : &'a [Token], index : &mut usize, scope : &mut Scope<'a>) -> (bool, i32) {
+        let mut packed = false;
+        let mut aligned = 0;
+        while Token::consume(s, &tokens[*index], index, "__attribute__") {
+            Token::expect(s, &tokens[*index], index, "(");
+            Token::expect(s, &tokens[*index], index, "(");
+            while !Token::consume(s, &tokens[*index], index, ")") {
+                let token = &tokens[*index];
+                *index += 1;
+                // __packed__はpackedと同じ属性を指す
+                let name = token.text();
+                match name.strip_prefix("__").and_then(|name| name.strip_suffix("__")).unwrap_or(name) {
+                    "packed" => packed = true,
+                    "aligned" => {
+                        let mut align = 16; // 引数がなければ、そのターゲットで最大のアラインメント
+                        if Token::consume(s, &tokens[*index], index, "(") {
+                            let token = &tokens[*index];
+                            align = Node::const_expr(s, tokens, index, scope);
+                            if align <= 0 || align & (align - 1) != 0 {
+                                Token::error_at(token, "アラインメントは2の累乗にしてください");
+                            }
+                            Token::expect(s, &tokens[*index], index, ")");
+                        }
+                        aligned = aligned.max(align as i32);
+                    }
+                    _ => Token::error_at(token, "未対応の属性です"),
                 }
+                Token::consume(s, &tokens[*index], index, ",");
+            }
+            Token::expect(s, &tokens[*index], index, ")");
+        }
+        (packed, aligned)
+    }
+
+    // 宣言子を読み、宣言される型と名前のトークンの位置(名前のない抽象宣言子ならNone)を返す
+    // int (*fp)(int)のように括弧で囲まれた宣言子は、括弧の後ろの型を先に読んでから中身を読む
+    fn declarator(s : &str, tokens : &'a [Token], index : &mut usize, scope : &mut Scope<'a>, ty : Type) -> (Type, Option<usize>) {
+        let (ty, name, _) = Node::func_declarator(s, tokens, index, scope, ty);
+        (ty, name)
+    }
+
+    // declaratorと同じだが、名前の直後の(引数)の名前と型も返す(関数定義で使う)
+    fn func_declarator(s : &str, tokens : &'a [Token], index : &mut usize, scope : &mut Scope<'a>, ty : Type) -> (Type, Option<usize>, Params) {
+        let ty = Node::pointers(s, tokens, index, ty);
+        if tokens[*index].is("(") && !tokens[*index + 1].is(")") && !Node::is_type(&tokens[*index + 1], scope) {
+            let start = *index + 1;
+            let mut depth = 0;
+            loop { // 対応する閉じ括弧まで読み飛ばす
+                let token = &tokens[*index];
+                if token.is("(") {
+                    depth += 1;
+                }
+                else if token.is(")") {
+                    depth -= 1;
+                }
+                else if Token::at_eof(token) {
+                    Token::error_at(&tokens[start - 1], "括弧が閉じられていません");
+                }
+                *index += 1;
+                if depth == 0 {
+                    break;
+                }
+            }
+            let ty = Node::type_suffix(s, tokens, index, scope, ty);
+            let end = *index;
+            *index = start;
+            let (ty, name, params) = Node::func_declarator(s, tokens, index, scope, ty);
+            Token::expect(s, &tokens[*index], index, ")");
+            *index = end;
+            return (ty, name, params);
+        }
+        let name = match tokens[*index].kind {
+            TokenKind::TKIdent(_) => {
+                *index += 1;
+                Some(*index - 1)
+            }
+            _ => None,
+        };
+        if Token::consume(s, &tokens[*index], index, "(") {
+            let (ty, params) = Node::func_params(s, tokens, index, scope, ty);
+            return (ty, name, params);
+        }
+        (Node::array_suffix(s, tokens, index, ty, scope), name, Vec::new())
+    }
+
+    // 宣言子に続く[N]や(引数)を読み、配列型や関数型にする
+    fn type_suffix(s : &str, tokens : &'a [Token], index : &mut usize, scope : &mut Scope<'a>, ty : Type) -> Type {
+        if Token::consume(s, &tokens[*index], index, "(") {
+            return Node::func_params(s, tokens, index, scope, ty).0;
+        }
+        Node::array_suffix(s, tokens, index, ty, scope)
+    }
+
+    // キャストやsizeofに書く型名を読む
+    fn type_name(s : &str, tokens : &'a [Token], index : &mut usize, scope : &mut Scope<'a>) -> Type {
+        let base = Node::base_type(s, tokens, index, scope);
+        let (ty, name) = Node::declarator(s, tokens, index, scope, base);
+        if let Some(name) = name {
+            Token::error_at(&tokens[name], "型名に名前は書けません");
+        }
+        ty
+    }
+
+    // 変数名に続く[N][M]...を読み、要素の型がtyの配列型を返す
+    fn array_suffix(s : &str, tokens : &'a [Token], index : &mut usize, ty : Type, scope : &mut Scope<'a>) -> Type {
+        if !Token::consume(s, &tokens[*index], index, "[") {
+            return ty;
+        }
+        let token = &tokens[*index];
+        let len = Node::const_expr(s, tokens, index, scope);
+        if len < 0 {
+            Token::error_at(token, "配列の要素数が負です");
+        }
+        Token::expect(s, &tokens[*index], index, "]");
+        let elem = Node::array_suffix(s, tokens, index, ty, scope);
+        Type::Array(Box::new(elem), len as usize)
+    }
+
+    // 関数名に続く引数の並びを読み、関数型と、引数名のトークンの位置(省略されていればNone)と引数の型の組を返す
+    // 戻り値や引数そのものの型修飾子は関数型には含めず、引数の型にだけ残す
+    fn func_params(s : &str, tokens : &'a [Token], index : &mut usize, scope : &mut Scope<'a>, ret : Type) -> (Type, Params) {
+        let ret = ty::unqual(&ret).clone();
+        let mut params : Vec<Type> = Vec::new();
+        let mut names : Params = Vec::new();
+        let mut variadic = false;
+        let prototyped = !tokens[*index].is(")");
+        if tokens[*index].is("void") && tokens[*index + 1].is(")") { // 引数なし
+            *index += 1;
+        }
+        while !Token::consume(s, &tokens[*index], index, ")") {
+            if Token::consume(s, &tokens[*index], index, "...") {
+                variadic = true;
+                Token::expect(s, &tokens[*index], index, ")");
+                break;
+            }
+            let base = Node::base_type(s, tokens, index, scope);
+            let ty = Node::pointers(s, tokens, index, base);
+            // プロトタイプ宣言では引数名を省略できる
+            let (arg_type, name) = match tokens[*index].kind {
+                TokenKind::TKIdent(_) if tokens[*index + 1].is("[") && tokens[*index + 2].is("]") => { // 要素数を省略した配列
+                    let name = *index;
+                    *index += 3;
+                    (Type::Ptr(Box::new(Node::array_suffix(s, tokens, index, ty, scope))), Some(name))
+                }
+                _ if tokens[*index].is("[") && tokens[*index + 1].is("]") => {
+                    *index += 2;
+                    (Type::Ptr(Box::new(Node::array_suffix(s, tokens, index, ty, scope))), None)
+                }
+                _ => Node::declarator(s, tokens, index, scope, ty),
+            };
+            match ty::unqual(&arg_type) {
+                Type::Void => Token::error_at(&tokens[*index - 1], "引数の型にvoidは使えません"),
+                Type::Struct(_) => Token::error_at(&tokens[*index - 1], "構造体の値渡しには対応していません"),
                 _ => (),
             }
+            // 配列の引数は先頭の要素を指すポインタとして、関数の引数は関数ポインタとして受け取る
+            let arg_type = ty::decay(&arg_type);
+            params.push(ty::unqual(&arg_type).clone());
+            names.push((name, arg_type));
+            Token::consume(s, &tokens[*index], index, ",");
         }
+        (Type::Func(Box::new(FuncType { ret, params, variadic, prototyped })), names)
+    }
+
+    // 関数を宣言する。以前の宣言と型が食い違っていたり、二度定義されたりしていればエラーにする
+    fn declare_func(tokens : &'a [Token], name_index : usize, ty : Type, define : bool, scope : &mut Scope<'a>) {
+        let token = &tokens[name_index];
+        let name = token.text();
+        let (ty, decl, defined) = match scope.find_local_name(name) {
+            Some(Ident::Func { ty : prev, decl, defined }) => {
+                if define && *defined {
+                    Token::error_with_note(token, &format!("関数{}が再定義されています", name), &tokens[*decl], "以前の定義はここです");
+                }
+                let ty = ty::composite_func(prev, &ty).unwrap_or_else(|| {
+                    Token::error_with_note(token, &format!("関数{}の型が以前の宣言と一致しません", name), &tokens[*decl], "以前の宣言はここです")
+                });
+                // 定義済みなら、定義の位置を覚えておく
+                (ty, if *defined { *decl } else { name_index }, define || *defined)
+            }
+            Some(_) => Token::error_at(token, "既に宣言された名前です"),
+            None => (ty, name_index, define),
+        };
+        scope.push_name(name, Ident::Func { ty, decl, defined });
     }
 
+    // 実引数を仮引数の型に変換する
+    // calleeは呼び出す関数の名前(関数ポインタによる呼び出しなら空)
+    fn convert_args(token : &Token, callee : &str, func : &FuncType, args : &mut [usize], tree : &mut Vec<Node<'a>>) {
+        if !func.prototyped { // 引数の型が分からなければ、そのまま渡す
+            return;
+        }
+        let name = callee;
+        if args.len() < func.params.len() || (!func.variadic && args.len() > func.params.len()) {
+            let expected = format!("{}{}", func.params.len(), if func.variadic { "以上" } else { "" });
+            Token::error_at(token, &format!("関数{}の引数の個数が正しくありません(期待: {}, 実際: {})", name, expected, args.len()));
+        }
+        for (i, (arg, param)) in args.iter_mut().zip(&func.params).enumerate() {
+            if ty::discards_qualifiers(param, &tree[*arg].ty) {
+                Token::error_at(token, &format!("関数{}の{}番目の引数で、ポインタの指す先の型修飾子が失われます", name, i + 1));
+            }
+            *arg = ty::assign_to(tree, *arg, param)
+                .unwrap_or_else(|| Token::error_at(token, &format!("関数{}の{}番目の引数の型が正しくありません", name, i + 1)));
+        }
+    }
+
+
+    // #pragma packは構造体のレイアウトを決めるときに遡って参照するので、ここでは読み飛ばす
+    fn skip_pragmas(tokens : &'a [Token], index : &mut usize) -> bool {
+        let start = *index;
+        while let TokenKind::TKPack(_) = tokens[*index].kind {
+            *index += 1;
+        }
+        *index != start
+    }
+
+    fn consume_block_end(s : &str, tokens : &'a [Token], index : &mut usize) -> bool {
+        Node::skip_pragmas(tokens, index);
+        Token::consume(s, &tokens[*index], index, "}")
+    }
 
-    fn program(s : &str, tokens : &'a Vec<Token>, index : &mut usize) -> Vec<Ast<'a>> {
+    fn program(s : &str, tokens : &'a [Token], index : &mut usize) -> Vec<Ast<'a>> {
         let mut code : Vec<Ast<'a>> = Vec::new();
+        let mut scope = Scope::new();
         while !Token::at_eof(&tokens[*index]) {
+            if Node::skip_pragmas(tokens, index) {
+                continue;
+            }
             let mut tree : Vec<Node> = Vec::new();
             let mut region = 0;
-            let mut map : HashMap<&'a str, VarInfo> = HashMap::new();
-            Node::init_map(&mut map, &code);
-            Node::definition(s, tokens, index, &mut tree, &mut map, &mut region);
+            Node::definition(s, tokens, index, &mut tree, &mut region, &mut scope);
+            if tree.is_empty() { // 型の宣言だけなら、関数は作られない
+                continue;
+            }
             region = (region + 15) / 16 * 16;
-            code.push(Ast { tree, map, region});
+            code.push(Ast { tree, vars : std::mem::take(&mut scope.vars), region});
         }
         code
     }
 
-    fn definition(s : &str, tokens : &'a Vec<Token>, index : &mut usize, tree : &mut Vec<Node<'a>>, map : &mut HashMap<&'a str, VarInfo>, region : &mut i32) {
-        let func_type = Node::find_type(s, tokens, index);        
-        let token = &tokens[*index];
-        match token.kind { // 関数名と引数を記録
-            TokenKind::TKIdent(func_name) => { //関数名
-                *index += 1;
-                Token::expect(s, &tokens[*index], index, "(");
-                let mut arguments : Vec<&'a str> = Vec::new();
-                while !Token::consume(s, &tokens[*index], index, ")") {
-                    let arg_type = Node::find_type(s, tokens, index);
+    fn definition(s : &str, tokens : &'a [Token], index : &mut usize, tree : &mut Vec<Node<'a>>, region : &mut i32, scope : &mut Scope<'a>) {
+        if Token::consume(s, &tokens[*index], index, "typedef") {
+            Node::typedef_decl(s, tokens, index, scope);
+            Token::expect(s, &tokens[*index], index, ";");
+            return;
+        }
+        let base = Node::base_type(s, tokens, index, scope);
+        if Token::consume(s, &tokens[*index], index, ";") { // 型の宣言だけ
+            return;
+        }
+        // int (*get(void))(int)のような関数も読めるように宣言子として読む
+        let start = &tokens[*index];
+        let (ty, name, names) = Node::func_declarator(s, tokens, index, scope, base);
+        let (name_index, func_type) = match (name, &ty) {
+            (Some(name_index), Type::Func(func)) => (name_index, func.ret.clone()),
+            _ => Token::error_at(start, "関数定義ではありません"),
+        };
+        let token = &tokens[name_index];
+        let func_name = token.text();
+        if let Type::Struct(_) = func_type {
+            Token::error_at(token, "構造体を返す関数には対応していません");
+        }
+        Node::declare_func(tokens, name_index, ty.clone(), tokens[*index].is("{"), scope);
+        if Token::consume(s, &tokens[*index], index, ";") { // プロトタイプ宣言
+            return;
+        }
 
-                    let token = &tokens[*index];
-                    *index += 1;
-                    match token.kind {
-                        TokenKind::TKIdent(arg) => { // 引数名
-                            arguments.push(arg);
-                            if map.get(arg).is_some() {
-                                Token::error_msg(s, token.index, "同じ名前の引数が使われています");
-                            }
-                            *region += ty::type_to_offset(&arg_type);
-                            map.insert(arg, VarInfo { ty: arg_type, offset: *region});
-                        }
-                        _ => Token::error_msg(s, token.index, "変数ではありません"),
-                    }
-                    Token::consume(s, &tokens[*index], index, ",");
-                }
-                map.insert(func_name, VarInfo { ty: func_type.clone(), offset: -1 });
-                Token::expect(s, &tokens[*index], index, "{"); // 関数本体の処理が始まる
-                let mut func_code : Vec<usize> = Vec::new();
-                while !Token::consume(s, &tokens[*index], index, "}") {
-                    func_code.push(Node::stmt(s, tokens, index, tree, map, region)); // statementごとにパース
-                    ty::type_of_node(tree, tree.len() - 1); // nodeに型情報を付加しておく
-                }
-                tree.push(Node::new(NodeKind::NDFnDef(func_name, arguments), func_code, func_type));
+        if names.len() > 6 { // 引数はレジスタだけで受け取る
+            Token::error_at(token, &format!("7個以上の引数を受け取る関数{}の定義には対応していません", func_name));
+        }
+        scope.enter();
+        let mut arguments : Vec<usize> = Vec::new();
+        for (name, arg_type) in names {
+            let token = &tokens[name.unwrap_or_else(|| Token::error_at(token, "引数の名前が省略されています"))];
+            let arg = token.text();
+            if scope.find_local_name(arg).is_some() {
+                Token::error_at(token, "同じ名前の引数が使われています");
+            }
+            let offset = Node::new_offset(region, &arg_type, 0);
+            arguments.push(scope.push_var(Some(arg), VarInfo { ty: arg_type, offset }));
+        }
+        let variadic = matches!(&ty, Type::Func(func) if func.variadic);
+        let va_area = variadic.then(|| { // 可変長引数の関数は、va_argで読めるように引数レジスタを保存しておく
+            let ty = Type::Array(Box::new(Type::ULong), 6);
+            let offset = Node::new_offset(region, &ty, 0);
+            scope.push_var(None, VarInfo { ty, offset })
+        });
+        Token::expect(s, &tokens[*index], index, "{"); // 関数本体の処理が始まる
+        let mut func_code : Vec<usize> = Vec::new();
+        while !Node::consume_block_end(s, tokens, index) {
+            func_code.push(Node::stmt(s, tokens, index, tree, region, scope)); // statementごとにパース
+            ty::type_of_node(tree, tree.len() - 1); // nodeに型情報を付加しておく
+        }
+        scope.leave();
+        // va_startには名前付きの引数の個数と、引数レジスタを保存した変数を渡す
+        for node in tree.iter_mut() {
+            if let NodeKind::NDVaStart(ref mut named, ref mut area) = node.kind {
+                let Some(va_area) = va_area else {
+                    Token::error_at(token, &format!("可変長引数の関数ではない{}でva_startを使っています", func_name));
+                };
+                *named = arguments.len();
+                *area = va_area;
             }
-            _ => {
-                Token::error_msg(s, token.index, "関数定義ではありません");
+        }
+        // 戻り値は関数の型に変換する
+        for ret in 0..tree.len() {
+            if tree[ret].kind != NodeKind::NDRet || tree[ret].indices[0] == usize::MAX {
+                continue;
+            }
+            if func_type == Type::Void {
+                Token::error_at(token, &format!("void型の関数{}が値を返しています", func_name));
+            }
+            if ty::discards_qualifiers(&func_type, &tree[tree[ret].indices[0]].ty) {
+                Token::error_at(token, &format!("{}の戻り値でポインタの指す先の型修飾子が失われます", func_name));
             }
+            tree[ret].indices[0] = ty::cast_to(tree, tree[ret].indices[0], &func_type);
         }
+        tree.push(Node::new(NodeKind::NDFnDef(func_name, arguments, va_area), func_code, func_type));
     }
 
 
-    fn stmt(s : &str, tokens : &'a Vec<Token>, index : &mut usize, tree : &mut Vec<Node<'a>>, map : &mut HashMap<&'a str, VarInfo>, region : &mut i32) -> usize {
+    fn stmt(s : &str, tokens : &'a [Token], index : &mut usize, tree : &mut Vec<Node<'a>>, region : &mut i32, scope : &mut Scope<'a>) -> usize {
+        Node::skip_pragmas(tokens, index); // if (x)の直後など、文の前に#pragma packがあってもよい
         let token = &tokens[*index];
         if Token::consume(s, token, index, "{") { // ブロック {}
             let mut vec : Vec<usize> = Vec::new();
-            while !Token::consume(s, &tokens[*index], index, "}") {
-                let index = Node::stmt(s, tokens, index, tree, map, region);
+            scope.enter();
+            while !Node::consume_block_end(s, tokens, index) {
+                let index = Node::stmt(s, tokens, index, tree, region, scope);
                 vec.push(index);
             }
+            scope.leave();
             tree.push(Node::new_init(NodeKind::NDBlock, vec));
         }
         else if Token::consume(s, token, index, "return") { // return文
-            let left_index = Node::expr(s, tokens, index, tree, map, region);
+            if Token::consume(s, &tokens[*index], index, ";") { // 値を返さない
+                tree.push(Node::new_ret(usize::MAX));
+                return tree.len() - 1;
+            }
+            let left_index = Node::expr(s, tokens, index, tree, region, scope);
             tree.push(Node::new_ret(left_index));
             Token::expect(s, &tokens[*index], index, ";");
         }
         else if Token::consume(s, token, index, "if") { // if文
             Token::expect(s, &tokens[*index], index, "(");
-            let cond_index = Node::expr(s, tokens, index, tree, map, region);
+            let cond_index = Node::expr(s, tokens, index, tree, region, scope);
             Token::expect(s, &tokens[*index], index, ")");
-            let stmt_if = Node::stmt(s, tokens, index, tree, map, region);
-            let mut stmt_else = std::usize::MAX;
+            let stmt_if = Node::stmt(s, tokens, index, tree, region, scope);
+            let mut stmt_else = usize::MAX;
             if Token::consume(s, &tokens[*index], index, "else") {
-                stmt_else = Node::stmt(s, tokens, index, tree, map, region);
+                stmt_else = Node::stmt(s, tokens, index, tree, region, scope);
             }
             let vec = vec![cond_index, stmt_if, stmt_else];
             tree.push(Node::new_init(NodeKind::NDIf, vec));
         }
         else if Token::consume(s, token, index, "while") { // while文
             Token::expect(s, &tokens[*index], index, "(");
-            let cond_index = Node::expr(s, tokens, index, tree, map, region);
+            let cond_index = Node::expr(s, tokens, index, tree, region, scope);
             Token::expect(s, &tokens[*index], index, ")");
-            let stmt_wh = Node::stmt(s, tokens, index, tree, map, region);
+            let stmt_wh = Node::stmt(s, tokens, index, tree, region, scope);
             let vec = vec![cond_index, stmt_wh];
             tree.push(Node::new_init(NodeKind::NDWh, vec));
         }
         else if Token::consume(s, token, index, "for") { // for文
-            let mut decl_index = std::usize::MAX;
-            let mut manip_index = std::usize::MAX;
-            let mut cond_index = std::usize::MAX;
+            let mut decl_index = usize::MAX;
+            let mut manip_index = usize::MAX;
+            let mut cond_index = usize::MAX;
 
             Token::expect(s, &tokens[*index], index, "(");
             if !Token::consume(s, &tokens[*index], index, ";") {
-                decl_index = Node::expr(s, tokens, index, tree, map, region);
+                decl_index = Node::expr(s, tokens, index, tree, region, scope);
                 Token::expect(s, &tokens[*index], index, ";");
             }
 
             if !Token::consume(s, &tokens[*index], index, ";") {
-                cond_index = Node::expr(s, tokens, index, tree, map, region);
+                cond_index = Node::expr(s, tokens, index, tree, region, scope);
                 Token::expect(s, &tokens[*index], index, ";");
             }
 
             if !Token::consume(s, &tokens[*index], index, ")") {
-                manip_index = Node::expr(s, tokens, index, tree, map, region);
+                manip_index = Node::expr(s, tokens, index, tree, region, scope);
                 Token::expect(s, &tokens[*index], index, ")");
             }
 
-            let stmt_index = Node::stmt(s, tokens, index, tree, map, region);
+            let stmt_index = Node::stmt(s, tokens, index, tree, region, scope);
             let vec = vec![decl_index, cond_index, manip_index, stmt_index];
             let node = Node::new_init(NodeKind::NDFor, vec);
             tree.push(node);
         }   
         else { // それ以外の文
-            Node::expr(s, tokens, index, tree, map, region);
+            Node::expr(s, tokens, index, tree, region, scope);
             Token::expect(s, &tokens[*index], index, ";");
         }
         tree.len() - 1
     }
 
-    fn expr(s : &str, tokens : &'a Vec<Token>, index : &mut usize, tree : &mut Vec<Node<'a>>, map : &mut HashMap<&'a str, VarInfo>, region : &mut i32) -> usize {
-        Node::assign(s, tokens, index, tree, map, region)
+    fn expr(s : &str, tokens : &'a [Token], index : &mut usize, tree : &mut Vec<Node<'a>>, region : &mut i32, scope : &mut Scope<'a>) -> usize {
+        Node::assign(s, tokens, index, tree, region, scope)
     }
 
-    fn assign(s : &str, tokens : &'a Vec<Token>, index : &mut usize, tree : &mut Vec<Node<'a>>, map : &mut HashMap<&'a str, VarInfo>, region : &mut i32) -> usize {
-        let left_index = Node::equality(s, tokens, index, tree, map, region);
+    fn assign(s : &str, tokens : &'a [Token], index : &mut usize, tree : &mut Vec<Node<'a>>, region : &mut i32, scope : &mut Scope<'a>) -> usize {
+        let left_index = Node::equality(s, tokens, index, tree, region, scope);
         let token = &tokens[*index];
         if Token::consume(s, token, index, "=") { // 代入文
-            let val_index = Node::assign(s, tokens, index, tree, map, region);
+            let val_index = Node::assign(s, tokens, index, tree, region, scope);
+            type_of_node(tree, val_index);
+            if ty::is_read_only(&Node::lvalue_type(tree, left_index, &scope.vars)) {
+                Token::error_at(token, "constで修飾された値には代入できません");
+            }
+            if ty::discards_qualifiers(&tree[left_index].ty, &tree[val_index].ty) {
+                Token::error_at(token, "代入によってポインタの指す先の型修飾子が失われます");
+            }
             let vec = vec![left_index, val_index];
             tree.push(Node::new_init(NodeKind::NDAs, vec));
         }
         tree.len() - 1
     }
 
-    fn equality(s : &str, tokens : &'a Vec<Token>, index : &mut usize, tree : &mut Vec<Node<'a>>, map : &mut HashMap<&'a str, VarInfo>, region : &mut i32) -> usize {
-        let mut lval_index = Node::relational(s, tokens, index, tree, map, region);
+    fn equality(s : &str, tokens : &'a [Token], index : &mut usize, tree : &mut Vec<Node<'a>>, region : &mut i32, scope : &mut Scope<'a>) -> usize {
+        let mut lval_index = Node::relational(s, tokens, index, tree, region, scope);
         loop {
             if Token::consume(s, &tokens[*index], index, "==") {
-                let rval_index = Node::relational(s, tokens, index, tree, map, region);
+                let rval_index = Node::relational(s, tokens, index, tree, region, scope);
                 let vec = vec![lval_index, rval_index];
                 tree.push(Node::new_init(NodeKind::NDEq, vec));
             }   
             else if Token::consume(s, &tokens[*index], index, "!=") {
-                let rval_index = Node::relational(s, tokens, index, tree, map, region);
+                let rval_index = Node::relational(s, tokens, index, tree, region, scope);
                 let vec = vec![lval_index, rval_index];
                 tree.push(Node::new_init(NodeKind::NDNEq, vec));
             }   
@@ -311,26 +1005,26 @@ impl<'a> Node<'a> {
         }
     }
 
-    fn relational(s : &str, tokens : &'a Vec<Token>, index : &mut usize, tree : &mut Vec<Node<'a>>, map : &mut HashMap<&'a str, VarInfo>, region : &mut i32) -> usize {
-        let mut lval_index = Node::add(s, tokens, index, tree, map, region);
+    fn relational(s : &str, tokens : &'a [Token], index : &mut usize, tree : &mut Vec<Node<'a>>, region : &mut i32, scope : &mut Scope<'a>) -> usize {
+        let mut lval_index = Node::add(s, tokens, index, tree, region, scope);
         loop {
             if Token::consume(s, &tokens[*index], index, "<=") {
-                let rval_index = Node::add(s, tokens, index, tree, map, region);
+                let rval_index = Node::add(s, tokens, index, tree, region, scope);
                 let vec = vec![lval_index, rval_index];
                 tree.push(Node::new_init(NodeKind::NDLeEq, vec));
             }   
             else if Token::consume(s, &tokens[*index], index, "<") {
-                let rval_index = Node::add(s, tokens, index, tree, map, region);
+                let rval_index = Node::add(s, tokens, index, tree, region, scope);
                 let vec = vec![lval_index, rval_index];
                 tree.push(Node::new_init(NodeKind::NDLe, vec));
             }   
             else if Token::consume(s, &tokens[*index], index, ">=") {
-                let rval_index = Node::add(s, tokens, index, tree, map, region);
+                let rval_index = Node::add(s, tokens, index, tree, region, scope);
                 let vec = vec![rval_index, lval_index];
                 tree.push(Node::new_init(NodeKind::NDLeEq, vec));
             }   
             else if Token::consume(s, &tokens[*index], index, ">") {
-                let rval_index = Node::add(s, tokens, index, tree, map, region);
+                let rval_index = Node::add(s, tokens, index, tree, region, scope);
                 let vec = vec![rval_index, lval_index];
                 tree.push(Node::new_init(NodeKind::NDLe, vec));
             }   
@@ -341,21 +1035,20 @@ impl<'a> Node<'a> {
         }
     }
 
-    fn add(s : &str, tokens : &'a Vec<Token>, index : &mut usize, tree : &mut Vec<Node<'a>>, map : &mut HashMap<&'a str, VarInfo>, region : &mut i32) -> usize {
-        let mut lval_index = Node::mul(s, tokens, index, tree, map, region);
+    fn add(s : &str, tokens : &'a [Token], index : &mut usize, tree : &mut Vec<Node<'a>>, region : &mut i32, scope : &mut Scope<'a>) -> usize {
+        let mut lval_index = Node::mul(s, tokens, index, tree, region, scope);
         loop {
-            if Token::consume(s, &tokens[*index], index, "+") {
-                let rval_index = Node::mul(s, tokens, index, tree, map, region);
-                let vec = vec![lval_index, rval_index];
+            let token = &tokens[*index];
+            if Token::consume(s, token, index, "+") {
+                let rval_index = Node::mul(s, tokens, index, tree, region, scope);
                 // オーバーロードに対応
-                let node = Node::new_add(tree, lval_index, rval_index, vec);
+                let node = Node::new_add(tree, lval_index, rval_index, token);
                 tree.push(node);
             }   
-            else if Token::consume(s, &tokens[*index], index, "-") {
-                let rval_index = Node::mul(s, tokens, index, tree, map, region);
-                let vec = vec![lval_index, rval_index];
+            else if Token::consume(s, token, index, "-") {
+                let rval_index = Node::mul(s, tokens, index, tree, region, scope);
                 // オーバーロードに対応
-                let node = Node::new_sub(tree, lval_index, rval_index, vec);
+                let node = Node::new_sub(tree, lval_index, rval_index, token);
                 tree.push(node);
             }   
             else {
@@ -365,16 +1058,16 @@ impl<'a> Node<'a> {
         }
     }
 
-    fn mul(s : &str, tokens : &'a Vec<Token>, index : &mut usize, tree : &mut Vec<Node<'a>>, map : &mut HashMap<&'a str, VarInfo>, region : &mut i32) -> usize {
-        let mut lval_index = Node::unary(s, tokens, index, tree, map, region);
+    fn mul(s : &str, tokens : &'a [Token], index : &mut usize, tree : &mut Vec<Node<'a>>, region : &mut i32, scope : &mut Scope<'a>) -> usize {
+        let mut lval_index = Node::unary(s, tokens, index, tree, region, scope);
         loop {
             if Token::consume(s, &tokens[*index], index, "*") {
-                let rval_index = Node::unary(s, tokens, index, tree, map, region);
+                let rval_index = Node::unary(s, tokens, index, tree, region, scope);
                 let vec = vec![lval_index, rval_index];
                 tree.push(Node::new_init(NodeKind::NDMul, vec));
             }   
             else if Token::consume(s, &tokens[*index], index, "/") {
-                let rval_index = Node::unary(s, tokens, index, tree, map, region);
+                let rval_index = Node::unary(s, tokens, index, tree, region, scope);
                 let vec = vec![lval_index, rval_index];
                 tree.push(Node::new_init(NodeKind::NDDiv, vec));
             }   
@@ -385,102 +1078,384 @@ impl<'a> Node<'a> {
         }
     }
 
-    fn unary(s : &str, tokens : &'a Vec<Token>, index : &mut usize, tree : &mut Vec<Node<'a>>, map : &mut HashMap<&'a str, VarInfo>, region : &mut i32) -> usize {
+    fn unary(s : &str, tokens : &'a [Token], index : &mut usize, tree : &mut Vec<Node<'a>>, region : &mut i32, scope : &mut Scope<'a>) -> usize {
         let token = &tokens[*index];
-        if Token::consume(s, token, index, "*") {
-            let addr_index = vec![Node::unary(s, tokens, index, tree, map, region)];
+        if token.is("(") && Node::is_type(&tokens[*index + 1], scope) { // キャスト (型名)式
+            *index += 1;
+            let ty = Node::type_name(s, tokens, index, scope);
+            Token::expect(s, &tokens[*index], index, ")");
+            let operand = Node::unary(s, tokens, index, tree, region, scope);
+            type_of_node(tree, operand);
+            let ty = ty::unqual(&ty).clone();
+            if matches!(ty, Type::Struct(_)) || matches!(tree[operand].ty, Type::Struct(_)) {
+                Token::error_at(token, "構造体の値はキャストできません");
+            }
+            tree.push(Node::new(NodeKind::NDCast, vec![operand], ty));
+        }
+        else if Token::consume(s, token, index, "*") {
+            let addr_index = vec![Node::unary(s, tokens, index, tree, region, scope)];
             tree.push(Node::new_init(NodeKind::NDDeref, addr_index));
         }
         else if Token::consume(s, token, index, "&") {
-            let var_index = vec![Node::unary(s, tokens, index, tree, map, region)];
-            tree.push(Node::new_init(NodeKind::NDAddr, var_index));
+            let var_index = Node::unary(s, tokens, index, tree, region, scope);
+            let ty = Type::Ptr(Box::new(Node::lvalue_type(tree, var_index, &scope.vars)));
+            tree.push(Node::new(NodeKind::NDAddr, vec![var_index], ty));
         }
-        else if Token::consume(s, token, index, "sizeof") {
-            let child_index = Node::unary(s, tokens, index, tree, map, region);
+        else if Token::consume(s, token, index, "sizeof") { // 結果はsize_tと同じunsigned long
+            if tokens[*index].is("(") && Node::is_type(&tokens[*index + 1], scope) { // sizeof(型名)
+                *index += 1;
+                let ty = Node::type_name(s, tokens, index, scope);
+                if ty::is_incomplete(&ty) {
+                    Token::error_at(token, "不完全な型の大きさは求められません");
+                }
+                Token::expect(s, &tokens[*index], index, ")");
+                tree.push(Node::new_num(ty::type_to_size(&ty) as i64, Type::ULong));
+                return tree.len() - 1;
+            }
+            let child_index = Node::unary(s, tokens, index, tree, region, scope);
             type_of_node(tree, child_index);
-            tree.push(Node::new_num(ty::type_to_size(&tree[child_index].ty), Type::Int));
+            tree.push(Node::new_num(ty::type_to_size(&tree[child_index].ty) as i64, Type::ULong));
+        }
+        else if Token::consume(s, token, index, "_Alignof") { // 結果はsize_tと同じunsigned long
+            let ty = if tokens[*index].is("(") && Node::is_type(&tokens[*index + 1], scope) { // _Alignof(型名)
+                *index += 1;
+                let ty = Node::type_name(s, tokens, index, scope);
+                Token::expect(s, &tokens[*index], index, ")");
+                ty
+            }
+            else { // GNU拡張: 式のアラインメント
+                let child_index = Node::unary(s, tokens, index, tree, region, scope);
+                type_of_node(tree, child_index);
+                tree[child_index].ty.clone()
+            };
+            if ty::is_incomplete(&ty) {
+                Token::error_at(token, "不完全な型のアラインメントは求められません");
+            }
+            tree.push(Node::new_num(ty::type_to_align(&ty) as i64, Type::ULong));
         }
         // -x = 0 - x
         else if Token::consume(s, token, index, "-") {
-            let lnode = Node::new_num(0, Type::Int); // we only have int type now
             let lval_index = tree.len();
-            tree.push(lnode);
-            let rval_index = Node::primary(s, tokens, index, tree, map, region);
-            let vec = vec![lval_index, rval_index];
-            tree.push(Node::new(NodeKind::NDSub, vec, Type::Int));
-        }
-        else if Token::consume(s, token, index, "+") {
-            Node::primary(s, tokens, index, tree, map, region);
+            tree.push(Node::new_num(0, Type::Int));
+            let rval_index = Node::postfix(s, tokens, index, tree, region, scope);
+            let node = Node::new_sub(tree, lval_index, rval_index, token);
+            tree.push(node);
         }
         else {
-            Node::primary(s, tokens, index, tree, map, region);
+            Token::consume(s, token, index, "+");
+            Node::postfix(s, tokens, index, tree, region, scope);
         }
 
         tree.len() - 1
     }
 
-    fn primary(s : &str, tokens : &'a Vec<Token>, index : &mut usize, tree : &mut Vec<Node<'a>>, map : &mut HashMap<&'a str, VarInfo>, region : &mut i32) -> usize {
+    fn postfix(s : &str, tokens : &'a [Token], index : &mut usize, tree : &mut Vec<Node<'a>>, region : &mut i32, scope : &mut Scope<'a>) -> usize {
+        let mut lval_index = Node::primary(s, tokens, index, tree, region, scope);
+        loop {
+            let token = &tokens[*index];
+            if Token::consume(s, token, index, "[") { // a[i]は*(a + i)
+                let rval_index = Node::expr(s, tokens, index, tree, region, scope);
+                Token::expect(s, &tokens[*index], index, "]");
+                let node = Node::new_add(tree, lval_index, rval_index, token);
+                tree.push(node);
+                tree.push(Node::new_init(NodeKind::NDDeref, vec![tree.len() - 1]));
+            }
+            else if Token::consume(s, token, index, "(") { // 関数ポインタによる呼び出し
+                type_of_node(tree, lval_index);
+                let func = match ty::decay(&tree[lval_index].ty) {
+                    Type::Ptr(ty) => match *ty {
+                        Type::Func(func) => func,
+                        _ => Token::error_at(token, "関数ではありません"),
+                    }
+                    _ => Token::error_at(token, "関数ではありません"),
+                };
+                let mut args = Node::call_args(s, tokens, index, tree, region, scope);
+                Node::convert_args(token, "", &func, &mut args, tree);
+                args.insert(0, lval_index);
+                tree.push(Node::new(NodeKind::NDFnPtrCall, args, func.ret));
+            }
+            else if Token::consume(s, token, index, ".") {
+                Node::struct_ref(tokens, index, tree, lval_index);
+            }
+            else if Token::consume(s, token, index, "->") { // p->xは(*p).x
+                tree.push(Node::new_init(NodeKind::NDDeref, vec![lval_index]));
+                Node::struct_ref(tokens, index, tree, tree.len() - 1);
+            }
+            else {
+                return lval_index;
+            }
+            lval_index = tree.len() - 1;
+        }
+    }
+
+    // 関数呼び出しの実引数を閉じ括弧まで読む
+    fn call_args(s : &str, tokens : &'a [Token], index : &mut usize, tree : &mut Vec<Node<'a>>, region : &mut i32, scope : &mut Scope<'a>) -> Vec<usize> {
+        let mut args : Vec<usize> = Vec::new();
+        while !Token::consume(s, &tokens[*index], index, ")") {
+            let arg = Node::expr(s, tokens, index, tree, region, scope);
+            type_of_node(tree, arg);
+            if let Type::Struct(_) = tree[arg].ty {
+                Token::error_at(&tokens[*index - 1], "構造体の値渡しには対応していません");
+            }
+            args.push(arg);
+            if args.len() > 6 { // 引数はレジスタだけで渡す
+                Token::error_at(&tokens[*index - 1], "7個以上の引数を渡す関数呼び出しには対応していません");
+            }
+            Token::consume(s, &tokens[*index], index, ",");
+        }
+        args
+    }
+
+    // va_listの引数を読む(va_listは構造体の配列なので、構造体を指すポインタになる)
+    fn va_list_arg(s : &str, tokens : &'a [Token], index : &mut usize, tree : &mut Vec<Node<'a>>, region : &mut i32, scope : &mut Scope<'a>) -> usize {
+        let token = &tokens[*index];
+        let ap = Node::assign(s, tokens, index, tree, region, scope);
+        type_of_node(tree, ap);
+        match ty::decay(&tree[ap].ty) {
+            Type::Ptr(elem) if matches!(*elem, Type::Struct(_)) => ap,
+            _ => Token::error_at(token, "va_listではありません"),
+        }
+    }
+
+    // 構造体の値struct_indexのメンバを参照するノードを作る
+    fn struct_ref(tokens : &'a [Token], index : &mut usize, tree : &mut Vec<Node<'a>>, struct_index : usize) {
+        type_of_node(tree, struct_index);
+        let token = &tokens[*index];
+        let info = match &tree[struct_index].ty {
+            Type::Struct(info) if info.borrow().complete => info.clone(),
+            Type::Struct(_) => Token::error_at(token, "不完全な型の構造体のメンバは参照できません"),
+            _ => Token::error_at(token, "構造体ではありません"),
+        };
+        let name = match token.kind {
+            TokenKind::TKIdent(name) => name,
+            _ => Token::error_at(token, "メンバ名ではありません"),
+        };
+        *index += 1;
+        let info = info.borrow();
+        let member = info.members.iter().find(|member| member.name == name)
+            .unwrap_or_else(|| Token::error_at(token, &format!("{}というメンバはありません", name)));
+        let kind = NodeKind::NDMember(member.offset, ty::qualifiers(&member.ty));
+        tree.push(Node::new(kind, vec![struct_index], ty::unqual(&member.ty).clone()));
+    }
+
+    // 左辺値index番目のノードが表すオブジェクトの、型修飾子を含む型
+    // 式の値の型(ノードの型)は修飾されないので、変数やポインタ、メンバの型から求める
+    fn lvalue_type(tree : &mut Vec<Node<'a>>, index : usize, vars : &[VarInfo]) -> Type {
+        type_of_node(tree, index);
+        match tree[index].kind {
+            NodeKind::NDLVa(var) => vars[var].ty.clone(),
+            NodeKind::NDDeref => match &tree[tree[index].indices[0]].ty {
+                Type::Ptr(ty) | Type::Array(ty, _) => (**ty).clone(),
+                ty => ty.clone(), // 関数
+            }
+            NodeKind::NDMember(_, quals) => { // 修飾された構造体のメンバは、同じ修飾子で修飾される
+                let parent = Node::lvalue_type(tree, tree[index].indices[0], vars);
+                ty::qualify(tree[index].ty.clone(), quals.union(&ty::qualifiers(&parent)))
+            }
+            _ => tree[index].ty.clone(),
+        }
+    }
+
+    fn primary(s : &str, tokens : &'a [Token], index : &mut usize, tree : &mut Vec<Node<'a>>, region : &mut i32, scope : &mut Scope<'a>) -> usize {
         let token = &tokens[*index];
         if Token::consume(s, token, index, "(") {
-            let id = Node::expr(s, tokens, index, tree, map, region);
+            let id = Node::expr(s, tokens, index, tree, region, scope);
             let token = &tokens[*index];
             Token::expect(s, token, index, ")");
             
             return id;
         }
-        else if Token::consume(s, token, index, "int") {
-            let mut ty = Type::Int;
-            while Token::consume(s, &tokens[*index], index, "*") {
-                ty = Type::Ptr(Box::new(ty));
+        else if Token::consume(s, token, index, "typedef") {
+            Node::typedef_decl(s, tokens, index, scope);
+            tree.push(Node::new_init(NodeKind::NDBlock, Vec::new()));
+        }
+        else if Node::is_type(token, scope) {
+            let align = Node::alignas(s, tokens, index, scope);
+            let base = Node::base_type(s, tokens, index, scope);
+            if tokens[*index].is(";") { // 構造体や列挙型の宣言だけなら、何もしない文になる
+                tree.push(Node::new_init(NodeKind::NDBlock, Vec::new()));
+                return tree.len() - 1;
+            }
+            let (ty, name) = Node::declarator(s, tokens, index, scope, base);
+            let token = &tokens[name.unwrap_or_else(|| Token::error_at(&tokens[*index], "変数ではありません"))];
+            let lvar_name = token.text();
+            if *ty::unqual(&ty) == Type::Void {
+                Token::error_at(token, "void型の変数は宣言できません");
+            }
+            if let Type::Func(_) = ty {
+                Token::error_at(token, "関数型の変数は宣言できません");
+            }
+            if scope.find_local_name(lvar_name).is_some() {
+                Token::error_at(token, "既に宣言された変数です");    
+            }
+            if ty::is_incomplete(&ty) {
+                Token::error_at(token, "不完全な型の変数は宣言できません");
             }
-            *region += ty::type_to_offset(&ty);
+            let offset = Node::new_offset(region, &ty, align);
+            let var = scope.push_var(Some(lvar_name), VarInfo { ty : ty.clone() , offset });
+            tree.push(Node::new_lvar(var, &ty));
             let token = &tokens[*index];
-            match token.kind {
-                TokenKind::TKIdent(lvar_name) => {
-                    if map.get(lvar_name).is_some() {
-                        Token::error_msg(s, token.index, "既に宣言された変数です");    
-                    }
-
-                    map.insert(lvar_name, VarInfo { ty : ty.clone() , offset: *region});
-                    tree.push(Node::new_lvar(lvar_name, ty));
-                    *index += 1;
-                }
-                _ => {
-                    Token::error_msg(s, token.index, "変数ではありません");
+            if Token::consume(s, token, index, "=") { // 初期化はconstで修飾された変数にもできる
+                let left_index = tree.len() - 1;
+                let val_index = Node::assign(s, tokens, index, tree, region, scope);
+                type_of_node(tree, val_index);
+                if ty::discards_qualifiers(&tree[left_index].ty, &tree[val_index].ty) {
+                    Token::error_at(token, "初期化によってポインタの指す先の型修飾子が失われます");
                 }
+                let vec = vec![left_index, val_index];
+                tree.push(Node::new_init(NodeKind::NDAs, vec));
             }
         }
         else{
             match token.kind { 
+                TokenKind::TKStr(_) => {
+                    let bytes = Node::string_literal(tokens, index);
+                    let ty = Type::Array(Box::new(Type::Char), bytes.len());
+                    tree.push(Node::new(NodeKind::NDStr(bytes), Vec::new(), ty));
+                }
+                TokenKind::TKIdent("__builtin_expect") => { // __builtin_expect(式, 期待値)
+                    *index += 1;
+                    Token::expect(s, &tokens[*index], index, "(");
+                    let expr_index = Node::assign(s, tokens, index, tree, region, scope);
+                    Token::expect(s, &tokens[*index], index, ",");
+                    // 期待値は分岐予測のヒントにすぎないので、解析した後に捨てる
+                    let len = tree.len();
+                    Node::assign(s, tokens, index, tree, region, scope);
+                    tree.truncate(len);
+                    Token::expect(s, &tokens[*index], index, ")");
+                    return expr_index;
+                }
+                TokenKind::TKIdent("__builtin_va_start") => { // __builtin_va_start(ap, 最後の仮引数)
+                    *index += 1;
+                    Token::expect(s, &tokens[*index], index, "(");
+                    let ap = Node::va_list_arg(s, tokens, index, tree, region, scope);
+                    Token::expect(s, &tokens[*index], index, ",");
+                    // 可変長引数の位置は名前付きの引数の個数から求めるので、最後の仮引数は解析した後に捨てる
+                    let len = tree.len();
+                    Node::assign(s, tokens, index, tree, region, scope);
+                    tree.truncate(len);
+                    Token::expect(s, &tokens[*index], index, ")");
+                    tree.push(Node::new(NodeKind::NDVaStart(0, 0), vec![ap], Type::Void));
+                }
+                TokenKind::TKIdent("__builtin_va_arg") => { // __builtin_va_arg(ap, 型名)
+                    *index += 1;
+                    Token::expect(s, &tokens[*index], index, "(");
+                    let ap = Node::va_list_arg(s, tokens, index, tree, region, scope);
+                    Token::expect(s, &tokens[*index], index, ",");
+                    let ty = ty::unqual(&Node::type_name(s, tokens, index, scope)).clone();
+                    if !ty::is_integer(&ty) && !matches!(ty, Type::Ptr(_)) {
+                        Token::error_at(token, "va_argで取り出せるのは整数とポインタだけです");
+                    }
+                    Token::expect(s, &tokens[*index], index, ")");
+                    tree.push(Node::new(NodeKind::NDVaArg, vec![ap], ty));
+                }
+                TokenKind::TKIdent("__builtin_va_copy") => { // __builtin_va_copy(dest, src)は*dest = *srcとして扱う
+                    *index += 1;
+                    Token::expect(s, &tokens[*index], index, "(");
+                    let dest = Node::va_list_arg(s, tokens, index, tree, region, scope);
+                    Token::expect(s, &tokens[*index], index, ",");
+                    let src = Node::va_list_arg(s, tokens, index, tree, region, scope);
+                    Token::expect(s, &tokens[*index], index, ")");
+                    tree.push(Node::new_init(NodeKind::NDDeref, vec![dest]));
+                    tree.push(Node::new_init(NodeKind::NDDeref, vec![src]));
+                    tree.push(Node::new_init(NodeKind::NDAs, vec![tree.len() - 2, tree.len() - 1]));
+                }
+                TokenKind::TKIdent("__builtin_va_end") => { // 後始末は必要ないので、apを評価するだけ
+                    *index += 1;
+                    Token::expect(s, &tokens[*index], index, "(");
+                    let ap = Node::va_list_arg(s, tokens, index, tree, region, scope);
+                    Token::expect(s, &tokens[*index], index, ")");
+                    return ap;
+                }
                 TokenKind::TKIdent(lvar_name) => { // function call
                     *index += 1;
-                    if Token::consume(s, &tokens[*index], index, "(") {
-                        let mut vec : Vec<usize> = Vec::new();
-                        while !Token::consume(s, &tokens[*index], index, ")") {
-                            vec.push(Node::expr(s, tokens, index, tree, map, region));
-                            Token::consume(s, &tokens[*index], index, ",");
+                    // 変数(関数ポインタ)の呼び出しは後置演算子として扱う
+                    let direct_call = tokens[*index].is("(") && matches!(scope.find_name(lvar_name), Some(Ident::Func { .. }) | None);
+                    if direct_call {
+                        if scope.find_name(lvar_name).is_none() { // 宣言されていない関数は、intを返す関数として暗黙に宣言する
+                            let func = FuncType { ret : Type::Int, params : Vec::new(), variadic : false, prototyped : false };
+                            scope.push_global_name(lvar_name, Ident::Func { ty : Type::Func(Box::new(func)), decl : *index - 1, defined : false });
                         }
-                        let var_info = map.entry(lvar_name).or_insert(VarInfo {ty : Type::Init, offset : -1});
-                        tree.push(Node::new(NodeKind::NDFnCall(lvar_name), vec, var_info.ty.clone()));
+                        *index += 1;
+                        let mut vec = Node::call_args(s, tokens, index, tree, region, scope);
+                        let func = match scope.find_name(lvar_name) {
+                            Some(Ident::Func { ty : Type::Func(func), .. }) => func,
+                            _ => Token::error_at(token, "関数ではありません"),
+                        };
+                        Node::convert_args(token, lvar_name, func, &mut vec, tree);
+                        let ty = func.ret.clone();
+                        tree.push(Node::new(NodeKind::NDFnCall(lvar_name), vec, ty));
                     }
                     else{
-                        if map.get(lvar_name).is_none() {
-                            Token::error_msg(s, token.index, "宣言されていない変数です");
+                        match scope.find_name(lvar_name) {
+                            Some(Ident::EnumConst(val)) => {
+                                tree.push(Node::new_num(*val, Type::Int));
+                                return tree.len() - 1;
+                            }
+                            Some(Ident::Func { ty, .. }) => {
+                                tree.push(Node::new(NodeKind::NDFunc(lvar_name), Vec::new(), ty.clone()));
+                                return tree.len() - 1;
+                            }
+                            _ => (),
                         }
-                        tree.push(Node::new_lvar(lvar_name,  map.get(lvar_name).unwrap().ty.clone()));
+                        let var = match scope.find_name(lvar_name) {
+                            Some(Ident::Var(var)) => *var,
+                            _ => Token::error_at(token, "宣言されていない変数です"),
+                        };
+                        tree.push(Node::new_lvar(var, &scope.vars[var].ty));
                     }
                 }
                 _ => {
-                    if true { // if int 
-                        tree.push(Node::new_num(Token::expect_number(s, token, index), Type::Int));
-                    }
+                    let val = Token::expect_number(s, token, index);
+                    tree.push(Node::new_num(val, Node::literal_type(token, val)));
                 }
             }
         }
         tree.len() - 1
     }
 
-    pub fn parse(s : &str, tokens : &'a Vec<Token>) -> Vec<Ast<'a>> {
+    // 整数定数の型は、接尾辞と値の大きさで決まる
+    fn literal_type(token : &Token, val : i64) -> Type {
+        let text = token.text().to_ascii_lowercase();
+        if text.starts_with('\'') { // 文字定数
+            return Type::Int;
+        }
+        let val = val as u64;
+        let unsigned = text.contains('u');
+        let long = text.contains('l');
+        let decimal = !text.starts_with('0'); // 10進数でなければ符号なしの型も候補になる
+        if !long && !unsigned && val <= i32::MAX as u64 {
+            Type::Int
+        }
+        else if !long && (unsigned || !decimal) && val <= u32::MAX as u64 {
+            Type::UInt
+        }
+        else if !unsigned && val <= i64::MAX as u64 {
+            Type::Long
+        }
+        else {
+            Type::ULong
+        }
+    }
+
+    // 隣接する文字列リテラルを連結し、エスケープシーケンスを解釈した内容を返す
+    fn string_literal(tokens : &'a [Token], index : &mut usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        while let TokenKind::TKStr(_) = tokens[*index].kind {
+            let token = &tokens[*index];
+            let mut i = token.index + 1;
+            while i < token.next_index - 1 {
+                let (c, next) = read_escaped_char(token.file, i);
+                bytes.push(c);
+                i = next;
+            }
+            *index += 1;
+        }
+        bytes.push(0);
+        bytes
+    }
+
+    pub fn parse(s : &str, tokens : &'a [Token]) -> Vec<Ast<'a>> {
         let mut index = 0;
         let functions = Node::program(s, tokens, &mut index);
         functions
@@ -0,0 +1,1438 @@
+use crate::parser::{SUPPORTED_ATTRIBUTES, SUPPORTED_BUILTINS};
+use crate::tokenizer::{SourceFile, Token, TokenKind};
+use std::collections::{HashMap, HashSet};
+use std::cell::Cell;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// __FILE__などの、展開するたびに値が決まるマクロ
+type MacroHandler<'a> = fn(&Preprocessor<'a>, &Token<'a>) -> Token<'a>;
+
+struct Macro<'a> {
+    def : Token<'a>, // #defineでマクロ名を指すトークン
+    params : Option<Vec<&'a str>>, // 関数形式マクロなら仮引数名
+    variadic : bool, // 可変長引数マクロなら、最後の仮引数が可変長引数を受け取る
+    body : Vec<Token<'a>>,
+    handler : Option<MacroHandler<'a>>,
+}
+
+#[derive(PartialEq, Eq)]
+enum CondCtx {
+    Then,
+    Elif,
+    Else,
+}
+
+// #if系ディレクティブの状態
+struct CondIncl<'a> {
+    directive : Token<'a>, // 開始した#if, #ifdef, #ifndef
+    ctx : CondCtx,
+    included : bool, // いずれかのグループが既に選ばれたか
+}
+
+// インクルードの統計(-ftime-report)
+#[derive(Default)]
+pub struct IncludeStats {
+    pub files_read : usize, // 読み込んでトークナイズしたファイルの数
+    pub bytes_read : usize,
+    pub skipped_by_once : usize, // #pragma onceにより省略したインクルードの数
+    pub skipped_by_guard : usize, // インクルードガードにより省略したインクルードの数
+    pub bytes_skipped : usize,
+}
+
+pub struct Preprocessor<'a> {
+    macros : HashMap<&'a str, Macro<'a>>,
+    pragma_once : HashSet<PathBuf>, // #pragma onceを含むファイル
+    include_guards : HashMap<PathBuf, (&'a str, usize)>, // インクルードガードで全体が囲まれたファイルの(マクロ名, 大きさ)
+    pub stats : IncludeStats,
+    pub warn_unknown_pragmas : bool, // -Wunknown-pragmas
+    pack : usize, // #pragma packで指定された構造体メンバの最大アラインメント(0なら既定)
+    pack_stack : Vec<usize>,
+    include_paths : Vec<PathBuf>, // -Iで指定されたディレクトリ
+    system_include_paths : Vec<PathBuf>, // システムのヘッダを置くディレクトリ(-Iの後に探す)
+    dependencies : Vec<(String, bool)>, // インクルードしたファイルと、それがシステムのヘッダかどうか(-M)
+    conds : Vec<CondIncl<'a>>,
+    include_stack : Vec<usize>, // インクルード中のファイルを開始した時点での条件のネストの深さ
+    counter : Cell<i64>, // __COUNTER__の次の値
+    date : String, // __DATE__の値
+    time : String, // __TIME__の値
+}
+
+// 常に定義されているマクロ
+const PREDEFINED_MACROS : [(&str, &str); 12] = [
+    ("__STDC__", "1"),
+    ("__STDC_VERSION__", "201112L"),
+    ("__STDC_HOSTED__", "1"),
+    ("__x86_64__", "1"),
+    ("__x86_64", "1"),
+    ("__LP64__", "1"),
+    ("_LP64", "1"),
+    ("__linux__", "1"),
+    ("__linux", "1"),
+    ("__unix__", "1"),
+    ("__unix", "1"),
+    ("__ELF__", "1"),
+];
+
+// #ifの中で使える、コンパイラの機能を調べる演算子
+const HAS_OPERATORS : [&str; 3] = ["__has_include", "__has_attribute", "__has_builtin"];
+
+// __has_attribute(name)または__has_attribute(scope::name)
+fn has_attribute(op : &Token, args : &[Token]) -> bool {
+    let name = match args {
+        [name] => name,
+        [scope, colon1, colon2, name] if ident_name(scope).is_some() && colon1.is(":") && colon2.is(":") => name,
+        _ => Token::error_at(op, "属性名ではありません"),
+    };
+    if ident_name(name).is_none() {
+        Token::error_at(name, "属性名ではありません");
+    }
+    // __packed__はpackedと同じ属性を指す
+    let text = name.text();
+    let text = text.strip_prefix("__").and_then(|t| t.strip_suffix("__")).unwrap_or(text);
+    SUPPORTED_ATTRIBUTES.contains(&text)
+}
+
+// マクロ呼び出しが現れたソース上の位置(マクロ展開中なら最も外側の呼び出し)
+fn use_site<'t, 'a>(tok : &'t Token<'a>) -> &'t Token<'a> {
+    let mut tok = tok;
+    while let Some(origin) = &tok.origin {
+        tok = origin;
+    }
+    tok
+}
+
+fn file_macro<'a>(_pp : &Preprocessor<'a>, tok : &Token<'a>) -> Token<'a> {
+    let name = use_site(tok).filename;
+    retokenize(tok, format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\"")), "ファイル名を展開できません")
+}
+
+fn line_macro<'a>(_pp : &Preprocessor<'a>, tok : &Token<'a>) -> Token<'a> {
+    retokenize(tok, use_site(tok).line.to_string(), "行番号を展開できません")
+}
+
+fn counter_macro<'a>(pp : &Preprocessor<'a>, tok : &Token<'a>) -> Token<'a> {
+    let val = pp.counter.get();
+    pp.counter.set(val + 1);
+    retokenize(tok, val.to_string(), "__COUNTER__を展開できません")
+}
+
+fn date_macro<'a>(pp : &Preprocessor<'a>, tok : &Token<'a>) -> Token<'a> {
+    retokenize(tok, pp.date.clone(), "__DATE__を展開できません")
+}
+
+fn time_macro<'a>(pp : &Preprocessor<'a>, tok : &Token<'a>) -> Token<'a> {
+    retokenize(tok, pp.time.clone(), "__TIME__を展開できません")
+}
+
+// 現在時刻(UTC)から__DATE__と__TIME__の値を作る
+fn date_and_time() -> (String, String) {
+    const MONTHS : [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64);
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // 1970-01-01からの日数を暦日に変換する
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    let date = format!("\"{} {:>2} {}\"", MONTHS[month as usize - 1], day, year);
+    let time = format!("\"{:02}:{:02}:{:02}\"", rem / 3600, rem % 3600 / 60, rem % 60);
+    (date, time)
+}
+
+const MAX_INCLUDE_DEPTH : usize = 200;
+
+fn ident_name<'a>(token : &Token<'a>) -> Option<&'a str> {
+    match token.kind {
+        TokenKind::TKIdent(name) => Some(name),
+        _ => None,
+    }
+}
+
+// マクロ本体などを複製し、展開元の情報を付け加える
+fn copy_token<'a>(token : &Token<'a>, hideset : &[&'a str], origin : &Token<'a>) -> Token<'a> {
+    let mut tok = token.clone();
+    for name in hideset {
+        if !tok.hideset.contains(name) {
+            tok.hideset.push(name);
+        }
+    }
+    tok.origin = Some(Box::new(origin.clone()));
+    tok
+}
+
+// 文字列を1つのトークンとして読み直す
+fn retokenize<'a>(token : &Token<'a>, text : String, msg : &str) -> Token<'a> {
+    let file = SourceFile::new("<scratch space>", text);
+    let mut tokens = Token::tokenize(file);
+    if tokens.len() != 2 {
+        Token::error_at(token, msg);
+    }
+    let mut tok = tokens.remove(0);
+    tok.at_bol = token.at_bol;
+    tok.has_space = token.has_space;
+    tok.hideset = token.hideset.clone();
+    tok.origin = token.origin.clone();
+    tok
+}
+
+// #演算子: 実引数の綴りを文字列リテラルにする
+fn stringize<'a>(hash : &Token<'a>, arg : &[Token<'a>]) -> Token<'a> {
+    let mut text = String::from("\"");
+    for (i, tok) in arg.iter().enumerate() {
+        if i > 0 && tok.has_space {
+            text.push(' ');
+        }
+        match tok.kind {
+            TokenKind::TKStr(_) => text.push_str(&tok.text().replace('\\', "\\\\").replace('"', "\\\"")),
+            _ if tok.text().starts_with('\'') => text.push_str(&tok.text().replace('\\', "\\\\").replace('"', "\\\"")),
+            _ => text.push_str(tok.text()),
+        }
+    }
+    text.push('"');
+    retokenize(hash, text, "文字列化できません")
+}
+
+// ##演算子: 2つのトークンを連結する
+fn paste<'a>(lhs : &Token<'a>, rhs : &Token<'a>) -> Token<'a> {
+    let text = format!("{}{}", lhs.text(), rhs.text());
+    retokenize(lhs, text, &format!("{}と{}を連結しても正しいトークンになりません", lhs.text(), rhs.text()))
+}
+
+// body[i]の__VA_OPT__に続く括弧を閉じる")"の位置
+fn va_opt_end(body : &[Token], i : usize) -> Option<usize> {
+    if !body.get(i + 1)?.is("(") {
+        return None;
+    }
+    let mut depth = 0;
+    for (j, tok) in body.iter().enumerate().skip(i + 1) {
+        if tok.is("(") {
+            depth += 1;
+        }
+        else if tok.is(")") {
+            depth -= 1;
+            if depth == 0 {
+                return Some(j);
+            }
+        }
+    }
+    None
+}
+
+// #ifの式中で、tokを値valの数として扱うように置き換える
+fn number_token<'a>(tok : &Token<'a>, val : i64) -> Token<'a> {
+    let mut num = tok.clone();
+    num.kind = TokenKind::TKNum(val);
+    num
+}
+
+// 条件が偽のグループを、対応する#elif, #else, #endifの直前まで読み飛ばす
+fn skip_cond_incl(stack : &mut Vec<Token>) {
+    let mut depth = 0;
+    while let Some(tok) = stack.last() {
+        if tok.kind == TokenKind::TKEof {
+            return;
+        }
+        if tok.is("#") && tok.at_bol && stack.len() >= 2 && !stack[stack.len() - 2].at_bol {
+            match ident_name(&stack[stack.len() - 2]) {
+                Some("if" | "ifdef" | "ifndef") => depth += 1,
+                Some("elif" | "else") if depth == 0 => return,
+                Some("endif") if depth == 0 => return,
+                Some("endif") => depth -= 1,
+                _ => (),
+            }
+        }
+        stack.pop();
+    }
+}
+
+// #errorなどのメッセージとして、トークン列を元の空白を保って連結する
+fn join_tokens(tokens : &[Token]) -> String {
+    let mut text = String::new();
+    for (i, tok) in tokens.iter().enumerate() {
+        if i > 0 && tok.has_space {
+            text.push(' ');
+        }
+        text.push_str(tok.text());
+    }
+    text
+}
+
+// 同じファイルを異なるパスでインクルードしても同一と分かるようにする
+fn canonical_path(path : &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+// ファイル全体が"#ifndef X / #define X / ... / #endif"で囲まれていれば、Xを返す
+fn detect_include_guard<'a>(tokens : &[Token<'a>]) -> Option<&'a str> {
+    let is_directive = |i : usize, name : &str| {
+        tokens[i].is("#") && tokens[i].at_bol && i + 1 < tokens.len() && !tokens[i + 1].at_bol && ident_name(&tokens[i + 1]) == Some(name)
+    };
+    if tokens.len() < 6 || !is_directive(0, "ifndef") || !is_directive(3, "define") {
+        return None;
+    }
+    let guard = ident_name(&tokens[2])?;
+    if ident_name(&tokens[5]) != Some(guard) {
+        return None;
+    }
+    let mut depth = 0;
+    for i in 6..tokens.len() {
+        if is_directive(i, "if") || is_directive(i, "ifdef") || is_directive(i, "ifndef") {
+            depth += 1;
+        }
+        else if depth == 0 && (is_directive(i, "elif") || is_directive(i, "else")) {
+            return None;
+        }
+        else if is_directive(i, "endif") {
+            if depth > 0 {
+                depth -= 1;
+                continue;
+            }
+            // #endifの行の後にはファイルの終端しかない
+            let rest = tokens[i + 2..].iter().find(|tok| tok.at_bol)?;
+            return if rest.kind == TokenKind::TKEof { Some(guard) } else { None };
+        }
+    }
+    None
+}
+
+// 現在の行の残りのトークンを取り出す
+fn read_line<'a>(stack : &mut Vec<Token<'a>>) -> Vec<Token<'a>> {
+    let mut line = Vec::new();
+    while let Some(tok) = stack.last() {
+        if tok.at_bol {
+            break;
+        }
+        line.push(stack.pop().unwrap());
+    }
+    line
+}
+
+impl<'a> Preprocessor<'a> {
+    pub fn new(include_paths : Vec<PathBuf>, system_include_paths : Vec<PathBuf>) -> Preprocessor<'a> {
+        let (date, time) = date_and_time();
+        let mut pp = Preprocessor {
+            macros : HashMap::new(),
+            pragma_once : HashSet::new(),
+            include_guards : HashMap::new(),
+            stats : IncludeStats::default(),
+            warn_unknown_pragmas : false,
+            pack : 0,
+            pack_stack : Vec::new(),
+            include_paths,
+            system_include_paths,
+            dependencies : Vec::new(),
+            conds : Vec::new(),
+            include_stack : Vec::new(),
+            counter : Cell::new(0),
+            date,
+            time,
+        };
+        for (name, value) in PREDEFINED_MACROS.iter() {
+            pp.define_macro(name, value);
+        }
+        let handlers : [(&str, MacroHandler<'a>); 5] = [
+            ("__FILE__", file_macro),
+            ("__LINE__", line_macro),
+            ("__COUNTER__", counter_macro),
+            ("__DATE__", date_macro),
+            ("__TIME__", time_macro),
+        ];
+        for (name, handler) in handlers.iter() {
+            pp.define_macro(name, "");
+            pp.macros.get_mut(name).unwrap().handler = Some(*handler);
+        }
+        pp
+    }
+
+    // コマンドラインの-Dなどから、"#define name body"と同様にマクロを定義する
+    pub fn define_macro(&mut self, name : &str, body : &str) {
+        let file = SourceFile::new("<built-in>", format!("{} {}", name, body));
+        let mut line = Token::tokenize(file);
+        line.pop(); // EOF
+        let name_tok = line[0].clone();
+        self.define(&name_tok, line);
+    }
+
+    pub fn undef_macro(&mut self, name : &str) {
+        self.macros.remove(name);
+    }
+
+    // 定義されているマクロを#define形式で名前順に列挙する
+    pub fn macro_definitions(&self, eof : &Token<'a>) -> Vec<String> {
+        let mut names : Vec<&&'a str> = self.macros.keys().collect();
+        names.sort();
+        names.iter().map(|name| {
+            let m = &self.macros[**name];
+            let mut def = format!("#define {}", name);
+            if let Some(params) = &m.params {
+                let mut params : Vec<String> = params.iter().map(|param| param.to_string()).collect();
+                if m.variadic {
+                    let last = params.pop().unwrap();
+                    params.push(if last == "__VA_ARGS__" { "...".to_string() } else { format!("{}...", last) });
+                }
+                def.push_str(&format!("({})", params.join(",")));
+            }
+            let body = match m.handler {
+                Some(handler) => vec![handler(self, eof)],
+                None => m.body.clone(),
+            };
+            for (i, tok) in body.iter().enumerate() {
+                if i == 0 || tok.has_space {
+                    def.push(' ');
+                }
+                def.push_str(tok.text());
+            }
+            def
+        }).collect()
+    }
+
+    // トークン列を受け取り、ディレクティブの処理とマクロ展開を行ったトークン列を返す
+    pub fn preprocess(&mut self, tokens : Vec<Token<'a>>) -> Vec<Token<'a>> {
+        let mut stack : Vec<Token<'a>> = tokens.into_iter().rev().collect();
+        let mut output : Vec<Token<'a>> = Vec::new();
+        while let Some(tok) = stack.pop() {
+            if tok.kind == TokenKind::TKEof {
+                // #ifと#endifの対応はファイルごとに閉じていなければならない
+                let depth = self.include_stack.pop().unwrap_or(0);
+                if let Some(cond) = self.conds.get(depth) {
+                    Token::error_at(&cond.directive, "対応する#endifがありません");
+                }
+                if stack.is_empty() {
+                    output.push(tok);
+                }
+                continue;
+            }
+            if tok.is("#") && tok.at_bol && tok.origin.is_none() {
+                self.directive(&tok, &mut stack);
+                continue;
+            }
+            if self.expand_macro(&tok, &mut stack) {
+                continue;
+            }
+            output.push(tok);
+        }
+        output
+    }
+
+    fn directive(&mut self, hash : &Token<'a>, stack : &mut Vec<Token<'a>>) {
+        let name_tok = match stack.last() {
+            Some(tok) if !tok.at_bol => stack.pop().unwrap(),
+            _ => return, // 空のディレクティブ
+        };
+        if let TokenKind::TKNum(_) = name_tok.kind { // # 行番号 "ファイル名" フラグ
+            let mut line = vec![name_tok.clone()];
+            line.extend(read_line(stack));
+            self.change_line(&name_tok, &line, stack);
+            return;
+        }
+        match ident_name(&name_tok) {
+            Some("include") => {
+                let line = read_line(stack);
+                self.include(hash, &name_tok, line, stack);
+            }
+            Some("define") => {
+                let line = read_line(stack);
+                self.define(&name_tok, line);
+            }
+            Some("undef") => {
+                let line = read_line(stack);
+                match line.first().and_then(ident_name) {
+                    Some(name) => { self.macros.remove(name); }
+                    None => Token::error_at(line.first().unwrap_or(&name_tok), "マクロ名ではありません"),
+                }
+            }
+            Some("if") => {
+                let line = read_line(stack);
+                let val = self.eval_const_expr(&name_tok, line);
+                self.start_cond(&name_tok, val, stack);
+            }
+            Some(directive @ ("ifdef" | "ifndef")) => {
+                let line = read_line(stack);
+                let defined = match line.first().and_then(ident_name) {
+                    Some(name) => self.is_defined(name),
+                    None => Token::error_at(line.first().unwrap_or(&name_tok), "マクロ名ではありません"),
+                };
+                self.start_cond(&name_tok, defined == (directive == "ifdef"), stack);
+            }
+            Some("elif") => {
+                let line = read_line(stack);
+                let cond = self.current_cond(&name_tok);
+                if cond.ctx == CondCtx::Else {
+                    Token::error_at(&name_tok, "#elseの後に#elifがあります");
+                }
+                cond.ctx = CondCtx::Elif;
+                if cond.included {
+                    skip_cond_incl(stack);
+                }
+                else if self.eval_const_expr(&name_tok, line) {
+                    self.current_cond(&name_tok).included = true;
+                }
+                else {
+                    skip_cond_incl(stack);
+                }
+            }
+            Some("else") => {
+                read_line(stack);
+                let cond = self.current_cond(&name_tok);
+                if cond.ctx == CondCtx::Else {
+                    Token::error_at(&name_tok, "#elseが重複しています");
+                }
+                cond.ctx = CondCtx::Else;
+                if cond.included {
+                    skip_cond_incl(stack);
+                }
+                cond.included = true;
+            }
+            Some("endif") => {
+                read_line(stack);
+                self.current_cond(&name_tok);
+                self.conds.pop();
+            }
+            Some("line") => {
+                let line = read_line(stack);
+                let args = self.expand_all(line);
+                self.change_line(&name_tok, &args, stack);
+            }
+            Some("error") => {
+                let line = read_line(stack);
+                Token::error_at(&name_tok, &format!("#error {}", join_tokens(&line)));
+            }
+            Some("warning") => {
+                let line = read_line(stack);
+                Token::warn_at(&name_tok, &format!("#warning {}", join_tokens(&line)));
+            }
+            Some("pragma") => {
+                let line = read_line(stack);
+                self.pragma(&name_tok, &line, stack);
+            }
+            _ => Token::error_at(&name_tok, "不正なディレクティブです"),
+        }
+    }
+
+    // 次の行の行番号とファイル名を、args(行番号と省略可能なファイル名)のとおりに変更する
+    fn change_line(&self, directive : &Token<'a>, args : &[Token<'a>], stack : &mut [Token<'a>]) {
+        let line = match args.first() {
+            Some(tok) if tok.text().chars().all(|c| c.is_ascii_digit()) => match tok.kind {
+                TokenKind::TKNum(line) if line > 0 => line,
+                _ => Token::error_at(tok, "行番号は正の整数でなければなりません"),
+            },
+            Some(tok) => Token::error_at(tok, "行番号ではありません"),
+            None => Token::error_at(directive, "行番号がありません"),
+        };
+        let filename : Option<&'a str> = match args.get(1) {
+            Some(Token { kind : TokenKind::TKStr(spelling), .. }) => {
+                let name = &spelling[1..spelling.len() - 1];
+                Some(&SourceFile::new(name, String::new()).name)
+            }
+            Some(tok) => Token::error_at(tok, "ファイル名ではありません"),
+            None => None,
+        };
+        let delta = line - (directive.line as i64 + 1);
+        for tok in stack.iter_mut().rev() {
+            if !std::ptr::eq(tok.file, directive.file) {
+                continue; // このファイルからインクルードされたファイル
+            }
+            tok.line = (tok.line as i64 + delta) as usize;
+            if let Some(name) = filename {
+                tok.filename = name;
+            }
+            if tok.kind == TokenKind::TKEof {
+                break;
+            }
+        }
+    }
+
+    fn pragma(&mut self, name_tok : &Token<'a>, line : &[Token<'a>], stack : &mut Vec<Token<'a>>) {
+        match line.first().and_then(ident_name) {
+            Some("once") => {
+                self.pragma_once.insert(canonical_path(Path::new(&name_tok.file.name)));
+            }
+            Some("pack") => {
+                let pack = self.pragma_pack(&line[0], &line[1..]);
+                // 構造体のレイアウトに反映するため、構文解析器に渡す
+                let mut tok = line[0].clone();
+                tok.kind = TokenKind::TKPack(pack);
+                tok.at_bol = true;
+                stack.push(tok);
+            }
+            _ => {
+                if self.warn_unknown_pragmas {
+                    Token::warn_at(line.first().unwrap_or(name_tok), "不明な#pragmaを無視します");
+                }
+            }
+        }
+    }
+
+    // #pragma pack(), pack(n), pack(push[, n]), pack(pop)を処理し、新しい最大アラインメント(0なら既定)を返す
+    fn pragma_pack(&mut self, pack_tok : &Token<'a>, args : &[Token<'a>]) -> usize {
+        let texts : Vec<&str> = args.iter().map(|tok| tok.text()).collect();
+        let alignment = |tok : &Token<'a>| match tok.kind {
+            TokenKind::TKNum(n) if [1, 2, 4, 8, 16].contains(&n) => n as usize,
+            _ => Token::error_at(tok, "アラインメントは1, 2, 4, 8, 16のいずれかでなければなりません"),
+        };
+        match texts.as_slice() {
+            ["(", ")"] => self.pack = 0,
+            ["(", "push", ")"] => self.pack_stack.push(self.pack),
+            ["(", "push", ",", _, ")"] => {
+                self.pack_stack.push(self.pack);
+                self.pack = alignment(&args[3]);
+            }
+            ["(", "pop", ")"] => {
+                self.pack = self.pack_stack.pop().unwrap_or_else(|| Token::error_at(&args[1], "対応するpack(push)がありません"));
+            }
+            ["(", _, ")"] => self.pack = alignment(&args[1]),
+            _ => Token::error_at(pack_tok, "#pragma packの形式が正しくありません"),
+        }
+        self.pack
+    }
+
+    fn start_cond(&mut self, directive : &Token<'a>, included : bool, stack : &mut Vec<Token<'a>>) {
+        self.conds.push(CondIncl { directive : directive.clone(), ctx : CondCtx::Then, included });
+        if !included {
+            skip_cond_incl(stack);
+        }
+    }
+
+    // 現在のファイル内で開始された、最も内側の#ifを返す
+    fn current_cond(&mut self, directive : &Token<'a>) -> &mut CondIncl<'a> {
+        let depth = self.include_stack.last().cloned().unwrap_or(0);
+        if self.conds.len() <= depth {
+            Token::error_at(directive, &format!("#{}に対応する#ifがありません", directive.text()));
+        }
+        self.conds.last_mut().unwrap()
+    }
+
+    // #ifと#elifの定数式を評価する
+    fn eval_const_expr(&self, directive : &Token<'a>, line : Vec<Token<'a>>) -> bool {
+        // defined演算子などはマクロ展開の前に置き換える
+        let replaced = self.replace_operators(line, true);
+        // マクロの展開結果に現れたdefinedは、GCCと同じく被演算子を展開せずに評価する
+        let mut stack : Vec<Token<'a>> = replaced.into_iter().rev().collect();
+        let mut expanded = Vec::new();
+        while let Some(tok) = stack.pop() {
+            if ident_name(&tok) == Some("defined") {
+                expanded.push(self.defined_operator(&tok, &mut || stack.pop()));
+            }
+            else if !self.expand_macro(&tok, &mut stack) {
+                expanded.push(tok);
+            }
+        }
+        // マクロの展開結果に含まれる__has_includeなども評価し、残った識別子は0とみなす
+        let tokens : Vec<Token<'a>> = self.replace_operators(expanded, false).into_iter().map(|tok| {
+            if ident_name(&tok).is_some() { number_token(&tok, 0) } else { tok }
+        }).collect();
+        if tokens.is_empty() {
+            Token::error_at(directive, "式がありません");
+        }
+        let mut expr = ConstExpr { tokens : &tokens, pos : 0 };
+        let val = expr.conditional(true);
+        if let Some(tok) = tokens.get(expr.pos) {
+            Token::error_at(tok, "余分なトークンがあります");
+        }
+        val.val != 0
+    }
+
+    // defined(with_definedのとき), __has_include, __has_attribute, __has_builtinを値に置き換える
+    fn replace_operators(&self, tokens : Vec<Token<'a>>, with_defined : bool) -> Vec<Token<'a>> {
+        let mut replaced : Vec<Token<'a>> = Vec::new();
+        let mut iter = tokens.into_iter();
+        while let Some(tok) = iter.next() {
+            match ident_name(&tok) {
+                Some("defined") if with_defined => replaced.push(self.defined_operator(&tok, &mut || iter.next())),
+                Some(op) if HAS_OPERATORS.contains(&op) => {
+                    if !matches!(iter.next(), Some(lparen) if lparen.is("(")) {
+                        Token::error_at(&tok, &format!("{}の後には(が必要です", op));
+                    }
+                    let mut args : Vec<Token<'a>> = Vec::new();
+                    let mut depth = 0;
+                    loop {
+                        let arg = iter.next().unwrap_or_else(|| Token::error_at(&tok, ")がありません"));
+                        if arg.is(")") && depth == 0 {
+                            break;
+                        }
+                        depth += arg.is("(") as i32 - arg.is(")") as i32;
+                        args.push(arg);
+                    }
+                    let val = match op {
+                        "__has_include" => {
+                            let (path, quoted) = self.header_name(&tok, args);
+                            self.search_include(&path, tok.file, quoted).is_some()
+                        }
+                        "__has_attribute" => has_attribute(&tok, &args),
+                        _ => match args.as_slice() {
+                            [name] if ident_name(name).is_some() => SUPPORTED_BUILTINS.contains(&name.text()),
+                            _ => Token::error_at(&tok, "組み込み関数名ではありません"),
+                        },
+                    };
+                    replaced.push(number_token(&tok, val as i64));
+                }
+                _ => replaced.push(tok),
+            }
+        }
+        replaced
+    }
+
+    // defined X, defined(X)の被演算子をnextから読み、値のトークンに置き換える
+    fn defined_operator(&self, tok : &Token<'a>, next : &mut dyn FnMut() -> Option<Token<'a>>) -> Token<'a> {
+        let mut name_tok = next().unwrap_or_else(|| Token::error_at(tok, "マクロ名がありません"));
+        let paren = name_tok.is("(");
+        if paren {
+            name_tok = next().unwrap_or_else(|| Token::error_at(tok, "マクロ名がありません"));
+        }
+        let name = ident_name(&name_tok).unwrap_or_else(|| Token::error_at(&name_tok, "マクロ名ではありません"));
+        if paren && !matches!(next(), Some(rparen) if rparen.is(")")) {
+            Token::error_at(&name_tok, ")がありません");
+        }
+        number_token(tok, self.is_defined(name) as i64)
+    }
+
+    // #ifdefやdefined演算子で定義済みとみなす名前か
+    fn is_defined(&self, name : &str) -> bool {
+        self.macros.contains_key(name) || HAS_OPERATORS.contains(&name)
+    }
+
+    fn define(&mut self, name_tok : &Token<'a>, line : Vec<Token<'a>>) {
+        let mut iter = line.into_iter().peekable();
+        let def = match iter.next() {
+            Some(tok) if ident_name(&tok).is_some() => tok,
+            Some(tok) => Token::error_at(&tok, "マクロ名ではありません"),
+            None => Token::error_at(name_tok, "マクロ名がありません"),
+        };
+        let name = ident_name(&def).unwrap();
+        let mut params = None;
+        let mut variadic = false;
+        if matches!(iter.peek(), Some(tok) if tok.is("(") && !tok.has_space) { // 関数形式マクロ
+            let lparen = iter.next().unwrap();
+            let mut names : Vec<&'a str> = Vec::new();
+            loop {
+                let tok = iter.next().unwrap_or_else(|| Token::error_at(&lparen, "仮引数リストが閉じられていません"));
+                if names.is_empty() && tok.is(")") {
+                    break;
+                }
+                if tok.is("...") {
+                    names.push("__VA_ARGS__");
+                    variadic = true;
+                }
+                else {
+                    match ident_name(&tok) {
+                        Some("__VA_ARGS__") => Token::error_at(&tok, "__VA_ARGS__は仮引数名に使えません"),
+                        Some(param) if !names.contains(&param) => names.push(param),
+                        Some(_) => Token::error_at(&tok, "同じ名前の仮引数が使われています"),
+                        None => Token::error_at(&tok, "仮引数名ではありません"),
+                    }
+                    // GNU拡張: "name..."は名前付きの可変長引数
+                    if matches!(iter.peek(), Some(next) if next.is("...")) {
+                        iter.next();
+                        variadic = true;
+                    }
+                }
+                let tok = iter.next().unwrap_or_else(|| Token::error_at(&lparen, "仮引数リストが閉じられていません"));
+                if tok.is(")") {
+                    break;
+                }
+                if variadic {
+                    Token::error_at(&tok, "可変長引数は最後の仮引数でなければなりません");
+                }
+                if !tok.is(",") {
+                    Token::error_at(&tok, ",ではありません");
+                }
+            }
+            params = Some(names);
+        }
+        let body : Vec<Token<'a>> = iter.collect();
+        if let (Some(first), Some(last)) = (body.first(), body.last()) {
+            if first.is("##") {
+                Token::error_at(first, "##はマクロ本体の先頭に置けません");
+            }
+            if last.is("##") {
+                Token::error_at(last, "##はマクロ本体の末尾に置けません");
+            }
+        }
+        for (i, tok) in body.iter().enumerate() {
+            match ident_name(tok) {
+                Some(name @ ("__VA_ARGS__" | "__VA_OPT__")) if !variadic => {
+                    Token::error_at(tok, &format!("{}は可変長引数マクロの中でのみ使えます", name))
+                }
+                Some("__VA_OPT__") => {
+                    let end = va_opt_end(&body, i).unwrap_or_else(|| Token::error_at(tok, "__VA_OPT__の後ろには括弧で囲んだトークン列が必要です"));
+                    if let Some(nested) = body[i + 1..end].iter().find(|tok| ident_name(tok) == Some("__VA_OPT__")) {
+                        Token::error_at(nested, "__VA_OPT__は入れ子にできません");
+                    }
+                    let content = &body[i + 2..end];
+                    if let (Some(first), Some(last)) = (content.first(), content.last()) {
+                        if first.is("##") {
+                            Token::error_at(first, "##は__VA_OPT__の中の先頭に置けません");
+                        }
+                        if last.is("##") {
+                            Token::error_at(last, "##は__VA_OPT__の中の末尾に置けません");
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        if let Some(names) = &params {
+            for (i, tok) in body.iter().enumerate() {
+                let is_param = body.get(i + 1).and_then(ident_name).is_some_and(|name| names.contains(&name) || (variadic && name == "__VA_OPT__"));
+                if tok.is("#") && !is_param {
+                    Token::error_at(tok, "#の後ろはマクロの仮引数でなければなりません");
+                }
+            }
+        }
+        self.macros.insert(name, Macro { def, params, variadic, body, handler : None });
+    }
+
+    fn include(&mut self, hash : &Token<'a>, name_tok : &Token<'a>, line : Vec<Token<'a>>, stack : &mut Vec<Token<'a>>) {
+        let (path, quoted) = self.header_name(name_tok, line);
+        let (found, system) = self.search_include(&path, hash.file, quoted).unwrap_or_else(|| {
+            Token::error_at(name_tok, &format!("{}が見つかりません", path))
+        });
+        let name = found.to_string_lossy().into_owned();
+        if !self.dependencies.iter().any(|(dep, _)| *dep == name) {
+            self.dependencies.push((name, system));
+        }
+
+        // 2回目以降のインクルードで中身が空になると分かっているファイルは、読み込まずに済ませる
+        let canonical = canonical_path(&found);
+        if self.pragma_once.contains(&canonical) {
+            self.stats.skipped_by_once += 1;
+            return;
+        }
+        if let Some((guard, size)) = self.include_guards.get(&canonical) {
+            if self.macros.contains_key(guard) {
+                self.stats.skipped_by_guard += 1;
+                self.stats.bytes_skipped += size;
+                return;
+            }
+        }
+
+        let contents = std::fs::read_to_string(&found).unwrap_or_else(|_| {
+            Token::error_at(name_tok, &format!("{}を読み込めません", found.display()))
+        });
+        if self.include_stack.len() >= MAX_INCLUDE_DEPTH {
+            Token::error_at(name_tok, "インクルードのネストが深すぎます");
+        }
+        self.stats.files_read += 1;
+        self.stats.bytes_read += contents.len();
+        let size = contents.len();
+        let file = SourceFile::new(&found.to_string_lossy(), contents);
+        let tokens = Token::tokenize(file);
+        if let Some(guard) = detect_include_guard(&tokens) {
+            self.include_guards.insert(canonical, (guard, size));
+        }
+        self.include_stack.push(self.conds.len());
+        stack.extend(tokens.into_iter().rev());
+    }
+
+    // "file"または<file>の形式のファイル名と、"file"の形式かどうかを返す
+    fn header_name(&self, name_tok : &Token<'a>, tokens : Vec<Token<'a>>) -> (String, bool) {
+        let mut tokens = tokens;
+        if matches!(tokens.first(), Some(tok) if ident_name(tok).is_some()) {
+            tokens = self.expand_all(tokens); // #include MACRO
+        }
+        match tokens.first() {
+            Some(Token { kind : TokenKind::TKStr(spelling), .. }) => (spelling[1..spelling.len() - 1].to_string(), true),
+            Some(tok) if tok.is("<") => {
+                let mut path = String::new();
+                for tok in &tokens[1..] {
+                    if tok.is(">") {
+                        return (path, false);
+                    }
+                    if tok.has_space && !path.is_empty() {
+                        path.push(' ');
+                    }
+                    path.push_str(tok.text());
+                }
+                Token::error_at(&tokens[0], ">がありません");
+            }
+            Some(tok) => Token::error_at(tok, "ファイル名ではありません"),
+            None => Token::error_at(name_tok, "ファイル名がありません"),
+        }
+    }
+
+    // "..."形式ならインクルード元のディレクトリから、次にインクルードパスから探す
+    // 見つかったファイルと、それがシステムのヘッダかどうかを返す
+    fn search_include(&self, path : &str, current : &SourceFile, quoted : bool) -> Option<(PathBuf, bool)> {
+        if Path::new(path).is_absolute() {
+            let candidate = PathBuf::from(path);
+            return candidate.is_file().then_some((candidate, false));
+        }
+        if quoted {
+            let dir = Path::new(&current.name).parent().unwrap_or_else(|| Path::new(""));
+            let candidate = dir.join(path);
+            if candidate.is_file() {
+                // システムのヘッダからインクルードされたファイルはシステムのヘッダとみなす
+                let system = self.dependencies.iter().any(|(dep, system)| *system && *dep == current.name);
+                return Some((candidate, system));
+            }
+        }
+        let user = self.include_paths.iter().map(|dir| (dir.join(path), false));
+        let system = self.system_include_paths.iter().map(|dir| (dir.join(path), true));
+        user.chain(system).find(|(candidate, _)| candidate.is_file())
+    }
+
+    // インクルードしたファイルを順に返す。include_systemが偽ならシステムのヘッダを除く
+    pub fn dependencies(&self, include_system : bool) -> Vec<&str> {
+        self.dependencies.iter().filter(|(_, system)| include_system || !system).map(|(dep, _)| dep.as_str()).collect()
+    }
+
+    // tokがマクロ名であれば展開した結果をstackに積み、trueを返す
+    fn expand_macro(&self, tok : &Token<'a>, stack : &mut Vec<Token<'a>>) -> bool {
+        let name = match ident_name(tok) {
+            Some(name) => name,
+            None => return false,
+        };
+        if tok.hideset.contains(&name) {
+            return false;
+        }
+        let m = match self.macros.get(name) {
+            Some(m) => m,
+            None => return false,
+        };
+
+        let (mut expanded, hideset) = match &m.params {
+            None if m.handler.is_some() => (vec![m.handler.unwrap()(self, tok)], vec![name]),
+            None => {
+                let mut hideset = tok.hideset.clone();
+                hideset.push(name);
+                (self.substitute(m, &m.body, &[], &[]), hideset)
+            }
+            Some(params) => {
+                if !matches!(stack.last(), Some(next) if next.is("(")) {
+                    return false; // 関数形式マクロの名前だけが現れた
+                }
+                let (args, rparen) = self.read_args(tok, m, params, stack);
+                let mut hideset : Vec<&'a str> = tok.hideset.iter().filter(|name| rparen.hideset.contains(name)).cloned().collect();
+                hideset.push(name);
+                (self.substitute(m, &m.body, params, &args), hideset)
+            }
+        };
+
+        for token in expanded.iter_mut() {
+            *token = copy_token(token, &hideset, tok);
+            token.at_bol = false;
+        }
+        if let Some(first) = expanded.first_mut() {
+            first.at_bol = tok.at_bol;
+            first.has_space = tok.has_space;
+        }
+        stack.extend(expanded.into_iter().rev());
+        true
+    }
+
+    // 関数形式マクロの実引数を読み、閉じ括弧のトークンと共に返す
+    fn read_args(&self, tok : &Token<'a>, m : &Macro<'a>, params : &[&'a str], stack : &mut Vec<Token<'a>>) -> (Vec<Vec<Token<'a>>>, Token<'a>) {
+        stack.pop(); // "("
+        let mut args : Vec<Vec<Token<'a>>> = vec![Vec::new()];
+        let mut depth = 0;
+        let rparen = loop {
+            let arg_tok = match stack.pop() {
+                Some(arg_tok) if arg_tok.kind != TokenKind::TKEof => arg_tok,
+                _ => Token::error_at(tok, "マクロ呼び出しが閉じられていません"),
+            };
+            if depth == 0 && arg_tok.is(")") {
+                break arg_tok;
+            }
+            // 可変長引数に入ってからのコンマは実引数の一部
+            if depth == 0 && arg_tok.is(",") && !(m.variadic && args.len() == params.len()) {
+                args.push(Vec::new());
+                continue;
+            }
+            if arg_tok.is("(") {
+                depth += 1;
+            }
+            else if arg_tok.is(")") {
+                depth -= 1;
+            }
+            args.last_mut().unwrap().push(arg_tok);
+        };
+        if params.is_empty() && args.len() == 1 && args[0].is_empty() {
+            args.clear();
+        }
+        if m.variadic && args.len() + 1 == params.len() {
+            args.push(Vec::new()); // 可変長引数の省略
+        }
+        if args.len() != params.len() {
+            let expected = if m.variadic { format!("{}以上", params.len() - 1) } else { params.len().to_string() };
+            Token::error_with_note(tok, &format!("マクロ{}の引数の個数が正しくありません(期待: {}, 実際: {})", tok.text(), expected, args.len()), &m.def, "マクロはここで定義されています");
+        }
+        (args, rparen)
+    }
+
+    // 仮引数を実引数で置き換え、#と##を処理する
+    fn substitute(&self, m : &Macro<'a>, body : &[Token<'a>], params : &[&'a str], args : &[Vec<Token<'a>>]) -> Vec<Token<'a>> {
+        let arg_of = |tok : Option<&Token<'a>>| -> Option<&Vec<Token<'a>>> {
+            let name = ident_name(tok?)?;
+            params.iter().position(|param| *param == name).map(|i| &args[i])
+        };
+        let is_va_opt = |tok : Option<&Token<'a>>| m.variadic && tok.and_then(ident_name) == Some("__VA_OPT__");
+        // Noneは連結のための空の実引数(placemarker)
+        let mut result : Vec<Option<Token<'a>>> = Vec::new();
+        let mut i = 0;
+        while i < body.len() {
+            let tok = &body[i];
+            if tok.is("#") && m.params.is_some() {
+                if is_va_opt(body.get(i + 1)) {
+                    let (content, next) = self.va_opt(m, body, i + 1, params, args);
+                    result.push(Some(stringize(tok, &content)));
+                    i = next;
+                }
+                else {
+                    result.push(Some(stringize(tok, arg_of(body.get(i + 1)).unwrap())));
+                    i += 2;
+                }
+                continue;
+            }
+            if tok.is("##") {
+                let lhs = result.pop().unwrap();
+                // GNU拡張: ", ## __VA_ARGS__"は、可変長引数が空ならコンマを取り除き、そうでなければ連結しない
+                let rhs_name = ident_name(&body[i + 1]);
+                if m.variadic && rhs_name.is_some() && rhs_name == params.last().cloned() && lhs.as_ref().is_some_and(|lhs| lhs.is(",")) {
+                    let va_args = args.last().unwrap();
+                    if !va_args.is_empty() {
+                        result.push(lhs);
+                        result.extend(va_args.iter().cloned().map(Some));
+                    }
+                    i += 2;
+                    continue;
+                }
+                let (mut rhs, next) = self.operand(m, body, i + 1, params, args);
+                let merged = match (lhs, rhs.remove(0)) {
+                    (Some(lhs), Some(rhs)) => Some(paste(&lhs, &rhs)),
+                    (lhs, None) => lhs,
+                    (None, rhs) => rhs,
+                };
+                result.push(merged);
+                result.extend(rhs);
+                i = next;
+                continue;
+            }
+            let (operand, next) = self.operand(m, body, i, params, args);
+            if body.get(next).is_some_and(|next| next.is("##")) {
+                // ##の被演算子はマクロ展開しない
+                result.extend(operand);
+            }
+            else if let Some(arg) = arg_of(Some(tok)) {
+                let mut expanded = self.expand_all(arg.clone());
+                if let Some(first) = expanded.first_mut() {
+                    first.has_space = tok.has_space;
+                }
+                result.extend(expanded.into_iter().map(Some));
+            }
+            else {
+                result.extend(operand);
+            }
+            i = next;
+        }
+        result.into_iter().flatten().collect()
+    }
+
+    // body[i]から始まる、##の被演算子となるトークン列と、その次の位置を返す
+    fn operand(&self, m : &Macro<'a>, body : &[Token<'a>], i : usize, params : &[&'a str], args : &[Vec<Token<'a>>]) -> (Vec<Option<Token<'a>>>, usize) {
+        let tok = &body[i];
+        let (tokens, next) = match ident_name(tok) {
+            Some("__VA_OPT__") if m.variadic => self.va_opt(m, body, i, params, args),
+            Some(name) if params.contains(&name) => {
+                let arg = &args[params.iter().position(|param| *param == name).unwrap()];
+                (arg.clone(), i + 1)
+            }
+            _ => return (vec![Some(tok.clone())], i + 1),
+        };
+        if tokens.is_empty() {
+            return (vec![None], next);
+        }
+        (tokens.into_iter().map(Some).collect(), next)
+    }
+
+    // __VA_OPT__(content): 可変長引数が空でなければcontentを置換した結果になり、空なら何も残さない
+    fn va_opt(&self, m : &Macro<'a>, body : &[Token<'a>], i : usize, params : &[&'a str], args : &[Vec<Token<'a>>]) -> (Vec<Token<'a>>, usize) {
+        let end = va_opt_end(body, i).unwrap();
+        let va_args = args.last().unwrap();
+        if self.expand_all(va_args.clone()).is_empty() {
+            return (Vec::new(), end + 1);
+        }
+        let mut content = self.substitute(m, &body[i + 2..end], params, args);
+        if let Some(first) = content.first_mut() {
+            first.has_space = body[i].has_space;
+        }
+        (content, end + 1)
+    }
+
+    // ディレクティブを処理せずに、トークン列中のマクロを全て展開する
+    fn expand_all(&self, tokens : Vec<Token<'a>>) -> Vec<Token<'a>> {
+        let mut stack : Vec<Token<'a>> = tokens.into_iter().rev().collect();
+        let mut output = Vec::new();
+        while let Some(tok) = stack.pop() {
+            if !self.expand_macro(&tok, &mut stack) {
+                output.push(tok);
+            }
+        }
+        output
+    }
+}
+
+// -E: プリプロセス後のトークン列を、行番号の対応を保ったCのソースとして出力する
+pub fn print_tokens(tokens : &[Token]) {
+    let mut out = String::new();
+    let mut files : Vec<&SourceFile> = tokens.last().map(|eof| eof.file).into_iter().collect(); // インクルードのネスト
+    let mut filename = files.first().map_or("", |file| file.name.as_str());
+    let mut line = 1;
+    if !filename.is_empty() {
+        out.push_str(&format!("# 1 \"{}\"\n", filename));
+    }
+    let mut prev : Option<&Token> = None;
+    for tok in tokens {
+        if tok.kind == TokenKind::TKEof {
+            break;
+        }
+        if let TokenKind::TKPack(pack) = tok.kind {
+            if !out.ends_with('\n') {
+                out.push('\n');
+            }
+            match pack {
+                0 => out.push_str("#pragma pack()\n"),
+                n => out.push_str(&format!("#pragma pack({})\n", n)),
+            }
+            line = tok.line + 1;
+            prev = None;
+            continue;
+        }
+        let site = use_site(tok);
+        let same_file = files.last().is_some_and(|file| std::ptr::eq(*file, site.file));
+        if !same_file || site.filename != filename || (tok.at_bol && (site.line < line || site.line > line + 8)) {
+            // 行番号を合わせられないので、行マーカーを出力する
+            let flag = if same_file {
+                ""
+            }
+            else if let Some(pos) = files.iter().position(|file| std::ptr::eq(*file, site.file)) {
+                files.truncate(pos + 1);
+                " 2" // インクルード元に戻った
+            }
+            else {
+                files.push(site.file);
+                " 1" // インクルードされたファイルに入った
+            };
+            if !out.ends_with('\n') {
+                out.push('\n');
+            }
+            out.push_str(&format!("# {} \"{}\"{}\n", site.line, site.filename, flag));
+            filename = site.filename;
+            line = site.line;
+            out.push_str(indentation(site));
+        }
+        else if tok.at_bol {
+            let min_newlines = if out.ends_with('\n') { 0 } else { 1 };
+            out.push_str(&"\n".repeat((site.line - line).max(min_newlines)));
+            line = site.line;
+            out.push_str(indentation(site));
+        }
+        else if tok.has_space || prev.is_some_and(|prev| Token::would_paste(prev.text(), tok.text())) {
+            out.push(' ');
+        }
+        out.push_str(tok.text());
+        prev = Some(tok);
+    }
+    if prev.is_some() {
+        out.push('\n');
+    }
+    print!("{}", out);
+}
+
+// -M: makeの規則として、targetsがdepsに依存することを出力する
+// phonyが真なら、ヘッダが削除されてもmakeが失敗しないように、main以外の依存先に空の規則を加える(-MP)
+pub fn dependency_rules(targets : &[String], main : Option<&str>, deps : &[&str], phony : bool) -> String {
+    // makeにとって特別な意味を持つ文字をエスケープする
+    let quote = |name : &str| name.replace('$', "$$").replace(' ', "\\ ").replace('#', "\\#");
+    let mut out = format!("{}:", targets.join(" "));
+    let mut width = out.len();
+    for dep in main.iter().chain(deps.iter()) {
+        let dep = quote(dep);
+        if width + 1 + dep.len() > 75 {
+            out.push_str(" \\\n");
+            width = 0;
+        }
+        out.push(' ');
+        out.push_str(&dep);
+        width += 1 + dep.len();
+    }
+    out.push('\n');
+    if phony {
+        for dep in deps {
+            out.push_str(&format!("\n{}:\n", quote(dep)));
+        }
+    }
+    out
+}
+
+// tokが行頭にあるとき、その行の字下げ
+fn indentation<'a>(tok : &Token<'a>) -> &'a str {
+    let contents = &tok.file.contents;
+    let start = contents[..tok.index].rfind('\n').map_or(0, |i| i + 1);
+    let indent = &contents[start..tok.index];
+    if indent.chars().all(|c| c == ' ' || c == '\t') { indent } else { "" }
+}
+
+// #ifの定数式の値。intmax_tまたはuintmax_tとして計算する
+#[derive(Clone, Copy)]
+struct Value {
+    val : i64,
+    unsigned : bool,
+}
+
+impl Value {
+    fn signed(val : i64) -> Value {
+        Value { val, unsigned : false }
+    }
+}
+
+// #ifの定数式の評価器
+// evalがfalseの部分式(短絡評価で評価されない側)では、0による除算などを報告しない
+struct ConstExpr<'t, 'a> {
+    tokens : &'t [Token<'a>],
+    pos : usize,
+}
+
+impl<'t, 'a> ConstExpr<'t, 'a> {
+    fn peek(&self) -> Option<&'t Token<'a>> {
+        self.tokens.get(self.pos)
+    }
+
+    fn consume(&mut self, op : &str) -> bool {
+        if self.peek().is_some_and(|tok| tok.is(op)) {
+            self.pos += 1;
+            true
+        }
+        else {
+            false
+        }
+    }
+
+    fn expect(&mut self, op : &str) {
+        if !self.consume(op) {
+            self.error(&format!("{}ではありません", op));
+        }
+    }
+
+    fn error(&self, msg : &str) -> ! {
+        match self.peek() {
+            Some(tok) => Token::error_at(tok, msg),
+            None => Token::error_at(self.tokens.last().unwrap(), &format!("式が途中で終わっています({})", msg)),
+        }
+    }
+
+    // conditional = logor ("?" conditional ":" conditional)?
+    fn conditional(&mut self, eval : bool) -> Value {
+        let cond = self.logor(eval);
+        if !self.consume("?") {
+            return cond;
+        }
+        let then = self.conditional(eval && cond.val != 0);
+        self.expect(":");
+        let els = self.conditional(eval && cond.val == 0);
+        let unsigned = then.unsigned || els.unsigned;
+        Value { val : if cond.val != 0 { then.val } else { els.val }, unsigned }
+    }
+
+    fn logor(&mut self, eval : bool) -> Value {
+        let mut lhs = self.logand(eval);
+        while self.consume("||") {
+            let rhs = self.logand(eval && lhs.val == 0);
+            lhs = Value::signed((lhs.val != 0 || rhs.val != 0) as i64);
+        }
+        lhs
+    }
+
+    fn logand(&mut self, eval : bool) -> Value {
+        let mut lhs = self.bitor(eval);
+        while self.consume("&&") {
+            let rhs = self.bitor(eval && lhs.val != 0);
+            lhs = Value::signed((lhs.val != 0 && rhs.val != 0) as i64);
+        }
+        lhs
+    }
+
+    fn bitor(&mut self, eval : bool) -> Value {
+        let mut lhs = self.bitxor(eval);
+        while self.consume("|") {
+            let rhs = self.bitxor(eval);
+            lhs = Value { val : lhs.val | rhs.val, unsigned : lhs.unsigned || rhs.unsigned };
+        }
+        lhs
+    }
+
+    fn bitxor(&mut self, eval : bool) -> Value {
+        let mut lhs = self.bitand(eval);
+        while self.consume("^") {
+            let rhs = self.bitand(eval);
+            lhs = Value { val : lhs.val ^ rhs.val, unsigned : lhs.unsigned || rhs.unsigned };
+        }
+        lhs
+    }
+
+    fn bitand(&mut self, eval : bool) -> Value {
+        let mut lhs = self.equality(eval);
+        while self.consume("&") {
+            let rhs = self.equality(eval);
+            lhs = Value { val : lhs.val & rhs.val, unsigned : lhs.unsigned || rhs.unsigned };
+        }
+        lhs
+    }
+
+    fn equality(&mut self, eval : bool) -> Value {
+        let mut lhs = self.relational(eval);
+        loop {
+            if self.consume("==") {
+                let rhs = self.relational(eval);
+                lhs = Value::signed((lhs.val == rhs.val) as i64);
+            }
+            else if self.consume("!=") {
+                let rhs = self.relational(eval);
+                lhs = Value::signed((lhs.val != rhs.val) as i64);
+            }
+            else {
+                return lhs;
+            }
+        }
+    }
+
+    fn relational(&mut self, eval : bool) -> Value {
+        let mut lhs = self.shift(eval);
+        loop {
+            let op = match self.peek() {
+                Some(tok) if tok.is("<") || tok.is("<=") || tok.is(">") || tok.is(">=") => tok.text(),
+                _ => return lhs,
+            };
+            self.pos += 1;
+            let rhs = self.shift(eval);
+            let ord = if lhs.unsigned || rhs.unsigned {
+                (lhs.val as u64).cmp(&(rhs.val as u64))
+            }
+            else {
+                lhs.val.cmp(&rhs.val)
+            };
+            let result = match op {
+                "<" => ord.is_lt(),
+                "<=" => ord.is_le(),
+                ">" => ord.is_gt(),
+                _ => ord.is_ge(),
+            };
+            lhs = Value::signed(result as i64);
+        }
+    }
+
+    fn shift(&mut self, eval : bool) -> Value {
+        let mut lhs = self.add(eval);
+        loop {
+            if self.consume("<<") {
+                let rhs = self.add(eval);
+                lhs.val = lhs.val.wrapping_shl(rhs.val as u32);
+            }
+            else if self.consume(">>") {
+                let rhs = self.add(eval);
+                lhs.val = if lhs.unsigned {
+                    (lhs.val as u64).wrapping_shr(rhs.val as u32) as i64
+                }
+                else {
+                    lhs.val.wrapping_shr(rhs.val as u32)
+                };
+            }
+            else {
+                return lhs;
+            }
+        }
+    }
+
+    fn add(&mut self, eval : bool) -> Value {
+        let mut lhs = self.mul(eval);
+        loop {
+            if self.consume("+") {
+                let rhs = self.mul(eval);
+                lhs = Value { val : lhs.val.wrapping_add(rhs.val), unsigned : lhs.unsigned || rhs.unsigned };
+            }
+            else if self.consume("-") {
+                let rhs = self.mul(eval);
+                lhs = Value { val : lhs.val.wrapping_sub(rhs.val), unsigned : lhs.unsigned || rhs.unsigned };
+            }
+            else {
+                return lhs;
+            }
+        }
+    }
+
+    fn mul(&mut self, eval : bool) -> Value {
+        let mut lhs = self.unary(eval);
+        loop {
+            let op = match self.peek() {
+                Some(tok) if tok.is("*") || tok.is("/") || tok.is("%") => tok,
+                _ => return lhs,
+            };
+            self.pos += 1;
+            let rhs = self.unary(eval);
+            let unsigned = lhs.unsigned || rhs.unsigned;
+            if op.is("*") {
+                lhs = Value { val : lhs.val.wrapping_mul(rhs.val), unsigned };
+                continue;
+            }
+            if rhs.val == 0 {
+                if eval {
+                    Token::error_at(op, "0による除算です");
+                }
+                lhs = Value { val : 0, unsigned };
+                continue;
+            }
+            let val = match (op.is("/"), unsigned) {
+                (true, true) => ((lhs.val as u64) / (rhs.val as u64)) as i64,
+                (true, false) => lhs.val.wrapping_div(rhs.val),
+                (false, true) => ((lhs.val as u64) % (rhs.val as u64)) as i64,
+                (false, false) => lhs.val.wrapping_rem(rhs.val),
+            };
+            lhs = Value { val, unsigned };
+        }
+    }
+
+    fn unary(&mut self, eval : bool) -> Value {
+        if self.consume("+") {
+            return self.unary(eval);
+        }
+        if self.consume("-") {
+            let val = self.unary(eval);
+            return Value { val : val.val.wrapping_neg(), unsigned : val.unsigned };
+        }
+        if self.consume("~") {
+            let val = self.unary(eval);
+            return Value { val : !val.val, unsigned : val.unsigned };
+        }
+        if self.consume("!") {
+            let val = self.unary(eval);
+            return Value::signed((val.val == 0) as i64);
+        }
+        self.primary(eval)
+    }
+
+    fn primary(&mut self, eval : bool) -> Value {
+        if self.consume("(") {
+            let val = self.conditional(eval);
+            self.expect(")");
+            return val;
+        }
+        match self.peek() {
+            Some(tok) => match tok.kind {
+                TokenKind::TKNum(val) => {
+                    self.pos += 1;
+                    // u接尾辞を持つか、intmax_tに収まらない整数定数はuintmax_t
+                    let text = tok.text().to_ascii_lowercase();
+                    let unsigned = text.starts_with(|c : char| c.is_ascii_digit()) && (text.contains('u') || val < 0);
+                    Value { val, unsigned }
+                }
+                _ => self.error("整数定数式ではありません"),
+            },
+            None => self.error("式がありません"),
+        }
+    }
+}
@@ -1,128 +1,424 @@
+use std::fmt;
+
 #[derive(PartialEq, Eq)]
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 pub enum TokenKind<'a> {
-    TKReserved(&'a str), 
+    TKReserved(&'a str),
     TKIdent(&'a str),
-    TKNum(i32), 
-    TKEof, 
+    TKNum(i64),
+    TKPPNum(&'a str), // 整数定数として解釈できないpp-number
+    TKStr(&'a str), // 文字列リテラル(引用符を含む綴り)
+    TKPack(usize), // #pragma packによる構造体メンバの最大アラインメント(0なら既定)
+    TKOther(&'a str), // 他のどのトークンにもならない1文字(@や\、閉じられていない引用符など)
+    TKEof,
+}
+
+// ソースファイル
+// トークンが内容を参照し続けるので、コンパイルが終わるまで解放しない
+pub struct SourceFile {
+    pub name : String,
+    pub contents : String, // 行の継続(バックスラッシュと改行)を取り除いた内容
+    raw : String, // 元の内容
+    splices : Vec<(usize, usize)>, // 取り除いた行の継続の(contents上の位置, 取り除いた長さ)
+}
+
+impl fmt::Debug for SourceFile {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SourceFile({})", self.name)
+    }
+}
+
+impl SourceFile {
+    pub fn new(name : &str, raw : String) -> &'static SourceFile {
+        let mut contents = String::with_capacity(raw.len());
+        let mut splices = Vec::new();
+        let mut rest = raw.as_str();
+        while let Some(i) = rest.find('\\') {
+            contents.push_str(&rest[..i]);
+            let len = if rest[i + 1..].starts_with('\n') { 2 } else if rest[i + 1..].starts_with("\r\n") { 3 } else { 0 };
+            if len == 0 {
+                contents.push('\\');
+                rest = &rest[i + 1..];
+                continue;
+            }
+            splices.push((contents.len(), len));
+            rest = &rest[i + len..];
+        }
+        contents.push_str(rest);
+        Box::leak(Box::new(SourceFile { name : name.to_string(), contents, raw, splices }))
+    }
+
+    // contents上の位置posに対応する、元の内容での位置
+    fn raw_pos(&self, pos : usize) -> usize {
+        pos + self.splices.iter().take_while(|(splice, _)| *splice <= pos).map(|(_, len)| len).sum::<usize>()
+    }
+
+    // contents上の位置posの、元の内容での行番号
+    pub fn line_of(&self, pos : usize) -> usize {
+        self.raw[..self.raw_pos(pos)].matches('\n').count() + 1
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Token<'a>{
     pub index : usize,
-    pub next_index : usize, 
+    pub next_index : usize,
     pub kind : TokenKind<'a>,
+    pub file : &'a SourceFile,
+    pub filename : &'a str, // 表示上のファイル名(#lineで変更される)
+    pub line : usize,
+    pub at_bol : bool, // 行頭のトークンか
+    pub has_space : bool, // 直前に空白があるか
+    pub hideset : Vec<&'a str>, // このトークンに対して展開済みのマクロ名
+    pub origin : Option<Box<Token<'a>>>, // マクロ展開で生成されたトークンなら、展開したマクロ呼び出し
 }
 
+// 長いものから順に照合する
+const PUNCTUATORS : [&str; 47] = [
+    "<<=", ">>=", "...",
+    "==", "!=", "<=", ">=", "->", "++", "--", "&&", "||", "<<", ">>",
+    "+=", "-=", "*=", "/=", "%=", "&=", "|=", "^=", "##",
+    "+", "-", "*", "/", "%", "(", ")", "[", "]", "{", "}", ";", ",", ".",
+    "&", "|", "^", "~", "!", "?", ":", "<", ">", "=",
+];
+
+// 二重音字と、それが表す区切り子
+const DIGRAPHS : [(&str, &str); 6] = [
+    ("%:%:", "##"), ("%:", "#"), ("<:", "["), (":>", "]"), ("<%", "{"), ("%>", "}"),
+];
 
+const KEYWORDS : [&str; 25] = [
+    "sizeof", "return", "if", "else", "while", "for", "void", "_Bool", "int", "char", "short", "long", "signed", "unsigned",
+    "struct", "union", "enum", "typedef", "const", "volatile", "restrict", "_Noreturn", "_Alignas", "_Alignof", "__attribute__",
+];
 
 impl<'a> Token<'a>{
-    pub fn new(kind : TokenKind, index : usize, next_index : usize) -> Token{
+    pub fn new(kind : TokenKind<'a>, file : &'a SourceFile, index : usize, next_index : usize, line : usize) -> Token<'a> {
         Token {
-            kind, 
-            index, 
+            kind,
+            index,
             next_index,
+            file,
+            filename : &file.name,
+            line,
+            at_bol : false,
+            has_space : false,
+            hideset : Vec::new(),
+            origin : None,
+        }
+    }
+
+    // ソース上の綴り
+    pub fn text(&self) -> &'a str {
+        &self.file.contents[self.index..self.next_index]
+    }
+
+    pub fn is(&self, op : &str) -> bool {
+        match self.kind {
+            TokenKind::TKReserved(sig) => sig == op,
+            _ => false,
         }
     }
 
     pub fn consume(_s : &str, token : &Token, index : &mut usize, op : &str) -> bool {
-        match token.kind {
-            TokenKind::TKReserved(sig) => {
-                if sig == op {
-                    *index += 1;
-                    true
-                }
-                else {
-                    false
-                }
-            }
-            _ => false
+        if token.is(op) {
+            *index += 1;
+            true
+        }
+        else {
+            false
         }
     }
 
-    pub fn expect(s : &str, token : &Token, index : &mut usize, op : &str) {
-        if let TokenKind::TKReserved(sig) = token.kind {
-            if sig == op {
-                *index += 1;
-                return;
-            }
+    pub fn expect(_s : &str, token : &Token, index : &mut usize, op : &str) {
+        if token.is(op) {
+            *index += 1;
+            return;
         }
-        eprintln!("{}", s);
-        eprintln!("{}^{}ではありません", " ".repeat(token.index), op);
-        std::process::exit(1);
+        Token::error_at(token, &format!("{}ではありません", op));
     }
 
-    pub fn expect_number(s : &str, token : &Token, index : &mut usize) -> i32 {
+    pub fn expect_number(_s : &str, token : &Token, index : &mut usize) -> i64 {
         *index += 1;
         match token.kind {
             TokenKind::TKNum(val) => val,
-            _ => {
-                eprintln!("{}", s);
-                eprintln!("{}^数ではありません", " ".repeat(token.index));
-                std::process::exit(1);
-            }
+            _ => Token::error_at(token, "数ではありません"),
+        }
+    }
+
+    // fileのpos文字目を指してエラーを報告する(行の継続を取り除く前の物理行を表示する)
+    fn report(file : &SourceFile, pos : usize, filename : &str, line : usize, msg : &str) {
+        let contents = &file.raw;
+        let pos = file.raw_pos(pos);
+        let start = contents[..pos].rfind('\n').map_or(0, |i| i + 1);
+        let end = contents[pos..].find('\n').map_or(contents.len(), |i| pos + i);
+        let loc = format!("{}:{}: ", filename, line);
+        eprintln!("{}{}", loc, &contents[start..end]);
+        eprintln!("{}^{}", " ".repeat(loc.chars().count() + contents[start..pos].chars().count()), msg);
+    }
+
+    // マクロ展開で生成されたトークンなら、展開された位置も併せて表示する
+    fn report_token(token : &Token, msg : &str) {
+        Token::report(token.file, token.index, token.filename, token.line, msg);
+        let mut origin = &token.origin;
+        while let Some(tok) = origin {
+            Token::report(tok.file, tok.index, tok.filename, tok.line, &format!("(マクロ{}の展開中)", tok.text()));
+            origin = &tok.origin;
         }
     }
 
-    pub fn error_msg(s : &str, pos : usize, msg : &str) {
-        eprintln!("{}", s);
-        eprintln!("{}^{}", " ".repeat(pos), msg);
+    pub fn error_msg(file : &SourceFile, pos : usize, msg : &str) -> ! {
+        Token::report(file, pos, &file.name, file.line_of(pos), msg);
+        std::process::exit(1);
+    }
+
+    pub fn warn_at(token : &Token, msg : &str) {
+        Token::report_token(token, &format!("警告: {}", msg));
+    }
+
+    pub fn error_at(token : &Token, msg : &str) -> ! {
+        Token::report_token(token, msg);
+        std::process::exit(1);
+    }
+
+    // エラーを報告した後、関連する位置(マクロの定義位置など)を補足として表示する
+    pub fn error_with_note(token : &Token, msg : &str, note_token : &Token, note : &str) -> ! {
+        Token::report_token(token, msg);
+        Token::report(note_token.file, note_token.index, note_token.filename, note_token.line, note);
         std::process::exit(1);
     }
 
+    // 空白を挟まずに並べると、別のトークンとして読まれてしまうか
+    pub fn would_paste(lhs : &str, rhs : &str) -> bool {
+        let (last, first) = match (lhs.chars().last(), rhs.chars().next()) {
+            (Some(last), Some(first)) => (last, first),
+            _ => return false,
+        };
+        let is_ident_char = |c : char| c.is_ascii_alphanumeric() || c == '_' || c == '.';
+        if is_ident_char(last) && is_ident_char(first) {
+            return true;
+        }
+        if last == '/' && (first == '/' || first == '*') {
+            return true;
+        }
+        let joined = format!("{}{}", lhs, rhs);
+        PUNCTUATORS.iter().chain(DIGRAPHS.iter().map(|(digraph, _)| digraph))
+            .any(|p| p.len() > lhs.len() && p.starts_with(lhs) && joined.starts_with(p))
+    }
+
     pub fn at_eof(token : &Token) -> bool { token.kind == TokenKind::TKEof }
 
-    pub fn tokenize(s : &'a str) -> Vec<Token<'a>> {
+    // プリプロセス後、予約語を識別子と区別する
+    // 1文字のトークンはマクロの引数や読み飛ばすグループの中でだけ使えるので、ここで残っていればエラーにする
+    pub fn convert_keywords(tokens : &mut [Token]) {
+        for token in tokens.iter_mut() {
+            match token.kind {
+                TokenKind::TKIdent(name) if KEYWORDS.contains(&name) => token.kind = TokenKind::TKReserved(name),
+                TokenKind::TKOther("'") | TokenKind::TKOther("\"") => Token::error_at(token, "リテラルが閉じられていません"),
+                TokenKind::TKOther(_) => Token::error_at(token, "トークナイズできません"),
+                _ => (),
+            }
+        }
+    }
+
+    pub fn tokenize(file : &'a SourceFile) -> Vec<Token<'a>> {
+        let s = file.contents.as_str();
+        let bytes = s.as_bytes();
         let mut sequence : Vec<Token<'a>> = Vec::new();
-        let mut next = 0;
-        for (i, c) in s.chars().enumerate() {
-            if next > i || char::is_whitespace(c) {
+        let mut i = 0;
+        let mut line = 1; // 改行の数から求めた行番号(行の継続は含まない)
+        let mut splices = 0; // これまでに現れた行の継続の数
+        let mut at_bol = true;
+        let mut has_space = false;
+        while i < bytes.len() {
+            let c = bytes[i];
+            if c == b'\n' {
+                i += 1;
+                line += 1;
+                at_bol = true;
+                has_space = false;
                 continue;
             }
-            else if c == '+' || c == '-' || c == '*' || c == '/' || c == '(' || c == ')' || c == '[' || c == ']' || c == ';' || c == '{' || c == '}' || c == ',' || c == '&' {
-                sequence.push(Token::new(TokenKind::TKReserved(&s[i..i+1]), i, i + 1));
+            if c.is_ascii_whitespace() {
+                i += 1;
+                has_space = true;
+                continue;
             }
-            else if c == '>' || c == '<' || c == '=' || c == '!' {
-                if i + 1 >= s.len() { Token::error_msg(s, i+1, "式になっていません"); }
-                if &s[i+1..i+2] == "=" {
-                    sequence.push(Token::new(TokenKind::TKReserved(&s[i..i+2]), i, i + 2));
-                    next = i + 2;
-                }
-                else {
-                    sequence.push(Token::new(TokenKind::TKReserved(&s[i..i+1]), i, i + 1));
+            if s[i..].starts_with("//") {
+                i = s[i..].find('\n').map_or(s.len(), |j| i + j);
+                has_space = true;
+                continue;
+            }
+            if s[i..].starts_with("/*") {
+                match s[i + 2..].find("*/") {
+                    Some(j) => {
+                        line += s[i..i + 2 + j].matches('\n').count();
+                        i += j + 4;
+                    }
+                    None => Token::error_msg(file, i, "コメントが閉じられていません"),
                 }
+                has_space = true;
+                continue;
             }
-            else if c.is_ascii_alphabetic() {
-                for (j, d) in (&s[i+1..]).chars().enumerate() {
-                    if d.is_ascii_alphabetic() || d.is_ascii_digit() || d == '_' { continue;}
-                    next = i + 1 + j;
-                    break;
+
+            let start = i;
+            let kind = if c.is_ascii_digit() || (c == b'.' && i + 1 < bytes.len() && bytes[i + 1].is_ascii_digit()) {
+                i = read_ppnumber(bytes, i);
+                match parse_integer(&s[start..i]) {
+                    Some(val) => TokenKind::TKNum(val as i64),
+                    None => TokenKind::TKPPNum(&s[start..i]),
                 }
-                if &s[i..next] == "sizeof" || &s[i..next] == "return" || &s[i..next] == "if" || &s[i..next] == "else" || &s[i..next] == "while" || &s[i..next] == "for" || &s[i..next] == "int" {
-                    sequence.push(Token::new(TokenKind::TKReserved(&s[i..next]), i, next));
+            }
+            else if let Some(end) = if c == b'"' || c == b'\'' { read_quoted(file, i, c) } else { None } {
+                i = end;
+                if c == b'"' {
+                    TokenKind::TKStr(&s[start..i])
                 }
                 else {
-                    sequence.push(Token::new(TokenKind::TKIdent(&s[i..next]), i, next));
+                    let (val, _) = read_escaped_char(file, start + 1);
+                    TokenKind::TKNum(val as i8 as i64)
                 }
             }
-            else if c.is_ascii_digit() {
-                let result = strtol(&s[i..]);
-                next = i + result.len();
-                sequence.push(Token::new(TokenKind::TKNum(result.parse().unwrap()), i, next));
+            else if c.is_ascii_alphabetic() || c == b'_' {
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                    i += 1;
+                }
+                TokenKind::TKIdent(&s[start..i])
+            }
+            else if let Some((digraph, punct)) = DIGRAPHS.iter().find(|(digraph, _)| s[i..].starts_with(digraph)) {
+                i += digraph.len();
+                TokenKind::TKReserved(punct)
+            }
+            else if c == b'#' {
+                i += if s[i..].starts_with("##") { 2 } else { 1 };
+                TokenKind::TKReserved(&s[start..i])
+            }
+            else {
+                match PUNCTUATORS.iter().find(|p| s[i..].starts_with(*p)) {
+                    Some(p) => {
+                        i += p.len();
+                        TokenKind::TKReserved(&s[start..i])
+                    }
+                    None => { // 他のどのトークンにもならない文字は、その1文字をトークンにする
+                        i += s[i..].chars().next().unwrap().len_utf8();
+                        TokenKind::TKOther(&s[start..i])
+                    }
+                }
+            };
+
+            while splices < file.splices.len() && file.splices[splices].0 <= start {
+                splices += 1;
             }
+            let mut token = Token::new(kind, file, start, i, line + splices);
+            token.at_bol = at_bol;
+            token.has_space = has_space;
+            sequence.push(token);
+            at_bol = false;
+            has_space = false;
         }
-        sequence.push(Token::new(TokenKind::TKEof, s.len(), s.len()));
+        let mut eof = Token::new(TokenKind::TKEof, file, s.len(), s.len(), line + file.splices.len());
+        eof.at_bol = true;
+        sequence.push(eof);
         sequence
     }
 
 }
 
+// pp-numberの終端を返す
+fn read_ppnumber(bytes : &[u8], mut i : usize) -> usize {
+    while i < bytes.len() {
+        let c = bytes[i];
+        if (c == b'e' || c == b'E' || c == b'p' || c == b'P') && i + 1 < bytes.len() && (bytes[i + 1] == b'+' || bytes[i + 1] == b'-') {
+            i += 2;
+        }
+        else if c.is_ascii_alphanumeric() || c == b'_' || c == b'.' {
+            i += 1;
+        }
+        else {
+            break;
+        }
+    }
+    i
+}
+
+// 10進・16進・8進の整数定数(u, lの接尾辞を許す)を読む
+pub fn parse_integer(s : &str) -> Option<u64> {
+    let lower = s.to_ascii_lowercase();
+    let body = lower.trim_end_matches(['u', 'l']);
+    let suffix = &lower[body.len()..];
+    if !matches!(suffix, "" | "u" | "l" | "ul" | "lu" | "ll" | "ull" | "llu") {
+        return None;
+    }
+    let (digits, radix) = if let Some(hex) = body.strip_prefix("0x") {
+        (hex, 16)
+    }
+    else if body.len() > 1 && body.starts_with('0') {
+        (&body[1..], 8)
+    }
+    else {
+        (body, 10)
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    u64::from_str_radix(digits, radix).ok()
+}
 
-pub fn strtol(s : &str) -> &str {
-    for (i, c) in s.chars().enumerate() {
-        if !c.is_ascii_digit() {
-            return &s[..i]
+// 引用符で囲まれたリテラルの終端(閉じ引用符の次)を返す(同じ行で閉じられていなければNone)
+fn read_quoted(file : &SourceFile, start : usize, quote : u8) -> Option<usize> {
+    let bytes = file.contents.as_bytes();
+    let mut i = start + 1;
+    while i < bytes.len() && bytes[i] != quote {
+        if bytes[i] == b'\n' {
+            break;
         }
+        i += if bytes[i] == b'\\' { 2 } else { 1 };
     }
-    s
-}
\ No newline at end of file
+    if i >= bytes.len() || bytes[i] != quote {
+        return None; // 閉じられていない引用符は1文字のトークンになる
+    }
+    Some(i + 1)
+}
+
+// エスケープシーケンスを解釈して1文字読み、(値, 次の位置)を返す
+pub fn read_escaped_char(file : &SourceFile, i : usize) -> (u8, usize) {
+    let bytes = file.contents.as_bytes();
+    if bytes[i] != b'\\' {
+        return (bytes[i], i + 1);
+    }
+    let c = bytes[i + 1];
+    if (b'0'..=b'7').contains(&c) {
+        let mut val : u32 = 0;
+        let mut j = i + 1;
+        while j < i + 4 && (b'0'..=b'7').contains(&bytes[j]) {
+            val = val * 8 + (bytes[j] - b'0') as u32;
+            j += 1;
+        }
+        return (val as u8, j);
+    }
+    if c == b'x' {
+        let mut val : u32 = 0;
+        let mut j = i + 2;
+        while bytes[j].is_ascii_hexdigit() {
+            val = val * 16 + (bytes[j] as char).to_digit(16).unwrap();
+            j += 1;
+        }
+        if j == i + 2 {
+            Token::error_msg(file, i, "不正なエスケープシーケンスです");
+        }
+        return (val as u8, j);
+    }
+    let val = match c {
+        b'a' => 7,
+        b'b' => 8,
+        b't' => b'\t',
+        b'n' => b'\n',
+        b'v' => 11,
+        b'f' => 12,
+        b'r' => b'\r',
+        b'e' => 27,
+        _ => c,
+    };
+    (val, i + 2)
+}
@@ -1,54 +1,414 @@
 use crate::parser::{NodeKind, Node};
+use std::{cell::RefCell, fmt, rc::Rc};
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Type {
+    Void,
+    Bool, // _Bool
     Int, 
-    Array,
+    UInt,
+    Char, // signed char
+    UChar, // unsigned char
+    Short,
+    UShort,
+    Long, // long long も同じ表現なのでLongとして扱う
+    ULong,
+    Array(Box<Type>, usize), // (要素の型, 要素数)
     Ptr(Box<Type>),
+    Enum, // 列挙型(intと同じ表現)
+    Func(Box<FuncType>),
+    Struct(Rc<RefCell<StructInfo>>),
+    Qual(Box<Type>, Qualifiers), // 修飾された型(配列は要素の型を修飾する)
     Init,
 }
 
-pub fn type_to_offset(ty : &Type) -> i32 {
+// 型修飾子
+// volatileな値の読み書きは、最適化を行うようになっても省いたり順序を入れ替えたりしてはならない
+// restrictは今は使っていないが、後の最適化のために型に残しておく
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Qualifiers {
+    pub is_const : bool,
+    pub is_volatile : bool,
+    pub is_restrict : bool,
+}
+
+impl Qualifiers {
+    pub fn is_empty(&self) -> bool {
+        !self.is_const && !self.is_volatile && !self.is_restrict
+    }
+
+    pub fn union(&self, other : &Qualifiers) -> Qualifiers {
+        Qualifiers {
+            is_const : self.is_const || other.is_const,
+            is_volatile : self.is_volatile || other.is_volatile,
+            is_restrict : self.is_restrict || other.is_restrict,
+        }
+    }
+
+    // otherの修飾子を全て含むか
+    fn contains(&self, other : &Qualifiers) -> bool {
+        self.union(other) == *self
+    }
+}
+
+// 関数の型
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct FuncType {
+    pub ret : Type,
+    pub params : Vec<Type>,
+    pub variadic : bool, // 引数の最後が...か
+    pub prototyped : bool, // 引数の型が宣言されているか(()で宣言された関数では宣言されていない)
+}
+
+// 構造体のメンバ
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Member {
+    pub name : String,
+    pub ty : Type,
+    pub offset : i32,
+    pub align : i32, // _Alignasで指定されたアラインメント(指定がなければ0)
+}
+
+// 構造体または共用体の定義
+// メンバを読み終える前に作られた型(自身を指すポインタのメンバなど)と共有し、読み終えたときに書き換える
+pub struct StructInfo {
+    pub id : usize, // 定義ごとに異なる番号
+    pub is_union : bool, // 共用体ならメンバは全て先頭に置かれる
+    pub members : Vec<Member>,
+    pub size : i32,
+    pub align : i32,
+    pub complete : bool, // メンバの定義を読み終えたか
+}
+
+// 同じ定義から作られた構造体型だけが等しい
+impl PartialEq for StructInfo {
+    fn eq(&self, other : &StructInfo) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for StructInfo {}
+
+// メンバが自身を指していても表示が終わるように、番号だけを表示する
+impl fmt::Debug for StructInfo {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "struct#{}", self.id)
+    }
+}
+
+impl StructInfo {
+    pub fn new(id : usize, is_union : bool) -> StructInfo {
+        StructInfo { id, is_union, members : Vec::new(), size : 0, align : 1, complete : false }
+    }
+
+    // System V ABIの規則でメンバを配置する
+    // packが0でなければメンバのアラインメントの上限、alignedが0でなければ構造体のアラインメントの下限
+    pub fn layout(&mut self, pack : i32, aligned : i32) {
+        let mut offset = 0;
+        let mut align = 1;
+        for member in self.members.iter_mut() {
+            let mut member_align = type_to_align(&member.ty).max(member.align);
+            if pack > 0 {
+                member_align = member_align.min(pack);
+            }
+            align = align.max(member_align);
+            if self.is_union { // 大きさは最も大きいメンバの大きさになる
+                member.offset = 0;
+                offset = offset.max(type_to_size(&member.ty));
+                continue;
+            }
+            offset = align_to(offset, member_align);
+            member.offset = offset;
+            offset += type_to_size(&member.ty);
+        }
+        self.align = align.max(aligned);
+        self.size = align_to(offset, self.align);
+        self.complete = true;
+    }
+}
+
+// 型修飾子を除いた型
+pub fn unqual(ty : &Type) -> &Type {
+    match ty {
+        Type::Qual(ty, _) => ty,
+        _ => ty,
+    }
+}
+
+// 型に付いている型修飾子
+pub fn qualifiers(ty : &Type) -> Qualifiers {
+    match ty {
+        Type::Qual(_, quals) => *quals,
+        _ => Qualifiers::default(),
+    }
+}
+
+// tyをqualsで修飾した型
+pub fn qualify(ty : Type, quals : Qualifiers) -> Type {
+    match ty {
+        _ if quals.is_empty() => ty,
+        Type::Array(elem, len) => Type::Array(Box::new(qualify(*elem, quals)), len),
+        Type::Qual(ty, old) => Type::Qual(ty, old.union(&quals)),
+        _ => Type::Qual(Box::new(ty), quals),
+    }
+}
+
+// 代入できない型か(constで修飾された型と、そのようなメンバを持つ構造体)
+pub fn is_read_only(ty : &Type) -> bool {
+    match ty {
+        Type::Qual(ty, quals) => quals.is_const || is_read_only(ty),
+        Type::Array(elem, _) => is_read_only(elem),
+        Type::Struct(info) => info.borrow().members.iter().any(|member| is_read_only(&member.ty)),
+        _ => false,
+    }
+}
+
+// rhsの値をlhs型に変換すると、ポインタの指す先の型修飾子が失われるか
+pub fn discards_qualifiers(lhs : &Type, rhs : &Type) -> bool {
+    match (lhs, decay(rhs)) {
+        (Type::Ptr(lhs), Type::Ptr(rhs)) => !qualifiers(lhs).contains(&qualifiers(&rhs)),
+        _ => false,
+    }
+}
+
+pub fn type_to_align(ty : &Type) -> i32 {
     match ty {
-        Type::Int => 8,
+        Type::Qual(ty, _) => type_to_align(ty),
+        Type::Void | Type::Func(_) => 1,
+        Type::Int | Type::UInt | Type::Enum => 4,
+        Type::Bool | Type::Char | Type::UChar => 1,
+        Type::Short | Type::UShort => 2,
+        Type::Long | Type::ULong => 8,
+        Type::Array(elem, _) => type_to_align(elem),
         Type::Ptr(_) => 8,
+        Type::Struct(info) => info.borrow().align,
         _ => -1
     }
 }
 
+// メンバの定義がまだない構造体(またはその配列)か
+pub fn is_incomplete(ty : &Type) -> bool {
+    match ty {
+        Type::Struct(info) => !info.borrow().complete,
+        Type::Array(elem, _) | Type::Qual(elem, _) => is_incomplete(elem),
+        _ => false,
+    }
+}
+
+pub fn is_integer(ty : &Type) -> bool {
+    matches!(ty, Type::Bool | Type::Int | Type::UInt | Type::Enum | Type::Char | Type::UChar | Type::Short | Type::UShort | Type::Long | Type::ULong)
+}
+
+pub fn is_unsigned(ty : &Type) -> bool {
+    matches!(ty, Type::Bool | Type::UInt | Type::UChar | Type::UShort | Type::ULong)
+}
+
+// 整数拡張: intより小さい整数型はintに変換される
+fn promote(ty : &Type) -> Type {
+    match ty {
+        Type::Bool | Type::Char | Type::UChar | Type::Short | Type::UShort | Type::Enum => Type::Int,
+        _ => ty.clone(),
+    }
+}
+
+// 通常の算術型変換で決まる、2つの整数型の共通の型
+pub fn common_type(lhs : &Type, rhs : &Type) -> Type {
+    let (lhs, rhs) = (promote(lhs), promote(rhs));
+    if lhs == Type::ULong || rhs == Type::ULong {
+        Type::ULong
+    }
+    else if lhs == Type::Long || rhs == Type::Long { // longはunsigned intの値を全て表せる
+        Type::Long
+    }
+    else if lhs == Type::UInt || rhs == Type::UInt {
+        Type::UInt
+    }
+    else {
+        Type::Int
+    }
+}
+
+// 定数の値を型tyで表せる値に変換する
+pub fn cast_value(val : i64, ty : &Type) -> i64 {
+    match ty {
+        Type::Bool => (val != 0) as i64,
+        Type::Int | Type::Enum => val as i32 as i64,
+        Type::UInt => val as u32 as i64,
+        Type::Char => val as i8 as i64,
+        Type::UChar => val as u8 as i64,
+        Type::Short => val as i16 as i64,
+        Type::UShort => val as u16 as i64,
+        _ => val,
+    }
+}
+
+// index番目のノードの値をty型に変換するノードを作り、そのインデックスを返す
+pub fn cast_to(tree : &mut Vec<Node>, index : usize, ty : &Type) -> usize {
+    if tree[index].ty == *ty {
+        return index;
+    }
+    tree.push(Node { kind : NodeKind::NDCast, indices : vec![index], ty : ty.clone() });
+    tree.len() - 1
+}
+
+// 二項演算の両辺を共通の型に変換し、その型を返す
+fn usual_arith_conv(tree : &mut Vec<Node>, index : usize) -> Type {
+    let (lhs, rhs) = (tree[index].indices[0], tree[index].indices[1]);
+    let ty = common_type(&tree[lhs].ty, &tree[rhs].ty);
+    tree[index].indices[0] = cast_to(tree, lhs, &ty);
+    tree[index].indices[1] = cast_to(tree, rhs, &ty);
+    ty
+}
+
+// 比較や代入ができる型の組か(整数同士は変換され、void *は他のポインタと相互に変換される)
+// 指す先の型修飾子の違いは、ここでは問わない
+fn compatible(lhs : &Type, rhs : &Type) -> bool {
+    match (decay(lhs), decay(rhs)) {
+        (Type::Ptr(lhs), Type::Ptr(rhs)) => {
+            let (lhs, rhs) = (unqual(&lhs), unqual(&rhs));
+            lhs == rhs || *lhs == Type::Void || *rhs == Type::Void
+        }
+        (Type::Bool, Type::Ptr(_)) => true, // ポインタは_Boolに変換できる
+        (lhs, rhs) => lhs == rhs || (is_integer(&lhs) && is_integer(&rhs)),
+    }
+}
+
+// index番目のノードが空ポインタ定数(整数定数0)か
+fn is_null_pointer_constant(tree : &[Node], index : usize) -> bool {
+    tree[index].kind == NodeKind::NDNum(0)
+}
+
+// 比較の一方がポインタで、もう一方が空ポインタ定数か
+fn compares_with_null(tree : &[Node], index : usize) -> bool {
+    let (lhs, rhs) = (tree[index].indices[0], tree[index].indices[1]);
+    let is_ptr = |index : usize| matches!(decay(&tree[index].ty), Type::Ptr(_));
+    (is_ptr(lhs) && is_null_pointer_constant(tree, rhs)) || (is_ptr(rhs) && is_null_pointer_constant(tree, lhs))
+}
+
+// index番目のノードの値を、代入と同じ規則でty型に変換する(変換できなければNone)
+pub fn assign_to(tree : &mut Vec<Node>, index : usize, ty : &Type) -> Option<usize> {
+    let null_pointer = matches!(ty, Type::Ptr(_)) && is_null_pointer_constant(tree, index);
+    if null_pointer || compatible(ty, &tree[index].ty) {
+        Some(cast_to(tree, index, ty))
+    }
+    else {
+        None
+    }
+}
+
+// 同じ関数の2つの宣言を合わせた型(両立しなければNone)
+// 引数の型を宣言していない宣言は、引数の型を宣言した宣言と両立する
+pub fn composite_func(lhs : &Type, rhs : &Type) -> Option<Type> {
+    match (lhs, rhs) {
+        (Type::Func(l), Type::Func(r)) if l.ret == r.ret => {
+            if !l.prototyped {
+                Some(rhs.clone())
+            }
+            else if !r.prototyped || (l.params == r.params && l.variadic == r.variadic) {
+                Some(lhs.clone())
+            }
+            else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+// 比較できる型の組か(構造体同士は比較できない)
+fn comparable(lhs : &Type, rhs : &Type) -> bool {
+    !matches!(lhs, Type::Struct(_)) && compatible(lhs, rhs)
+}
+
+// スカラ値を条件として使う場合は、_Boolへ変換したものとして扱う(0と等しくなければ真)
+fn convert_condition(tree : &mut Vec<Node>, index : usize, k : usize) {
+    let cond = tree[index].indices[k];
+    if cond < tree.len() {
+        if let Type::Struct(_) = tree[cond].ty {
+            eprintln!("構造体の値を条件に使っています");
+            std::process::exit(1);
+        }
+        tree[index].indices[k] = cast_to(tree, cond, &Type::Bool);
+    }
+}
+
+// 比較する整数同士は共通の型に揃える
+fn convert_operands(tree : &mut Vec<Node>, index : usize) {
+    let (lhs, rhs) = (tree[index].indices[0], tree[index].indices[1]);
+    if is_integer(&tree[lhs].ty) && is_integer(&tree[rhs].ty) {
+        usual_arith_conv(tree, index);
+    }
+}
+
+// 式の中の配列は、先頭の要素を指すポインタとして扱う
+pub fn decay(ty : &Type) -> Type {
+    match ty {
+        Type::Array(elem, _) => Type::Ptr(elem.clone()),
+        Type::Func(_) => Type::Ptr(Box::new(ty.clone())),
+        _ => ty.clone(),
+    }
+}
+
+// nをalignの倍数に切り上げる
+pub fn align_to(n : i32, align : i32) -> i32 {
+    (n + align - 1) / align * align
+}
+
 pub fn type_to_size(ty : &Type) -> i32 {
     match ty {
-        Type::Int => 4,
+        Type::Qual(ty, _) => type_to_size(ty),
+        Type::Void | Type::Func(_) => 1, // GCCと同じく、void *の演算では1バイトとして扱う
+        Type::Int | Type::UInt | Type::Enum => 4,
+        Type::Bool | Type::Char | Type::UChar => 1,
+        Type::Short | Type::UShort => 2,
+        Type::Long | Type::ULong => 8,
+        Type::Array(elem, len) => type_to_size(elem) * *len as i32,
         Type::Ptr(_) => 8,
+        Type::Struct(info) => info.borrow().size,
         _ => -1
     }
 }
 
 
 pub fn type_of_node(tree : &mut Vec<Node>, index : usize) {
-    if index >= tree.len() || tree[index].ty != Type::Init { return; }
+    if index >= tree.len() { return; }
     
+    // 型が決まっているノードでも、子の型はまだ決まっていないことがある(単項の-など)
     let indices = tree[index].indices.clone();
     for child_index in indices {
         type_of_node(tree, child_index);
     }
+    if tree[index].ty != Type::Init { return; }
     
     match tree[index].kind {
+        NodeKind::NDIf | NodeKind::NDWh => convert_condition(tree, index, 0),
+        NodeKind::NDFor => convert_condition(tree, index, 1),
         NodeKind::NDAs => {
-            if tree[*tree[index].indices.first().unwrap()].ty == tree[*tree[index].indices.last().unwrap()].ty {
-                tree[index].ty = tree[*tree[index].indices.first().unwrap()].ty.clone();
+            if let Type::Array(..) = tree[*tree[index].indices.first().unwrap()].ty {
+                eprintln!("配列に代入することはできません");
+                std::process::exit(1);
+            }
+            let (lhs, rhs) = (tree[index].indices[0], tree[index].indices[1]);
+            let null_pointer = matches!(tree[lhs].ty, Type::Ptr(_)) && is_null_pointer_constant(tree, rhs);
+            if null_pointer || compatible(&tree[lhs].ty, &tree[rhs].ty) {
+                tree[index].ty = tree[lhs].ty.clone();
+                if tree[index].ty == Type::Bool { // _Boolへの代入は0か1に正規化する
+                    tree[index].indices[1] = cast_to(tree, tree[index].indices[1], &Type::Bool);
+                }
             }
             else {
                 eprintln!("異なる型の値を代入しています");
-                // std::process::exit(1);
+                std::process::exit(1);
             }
         }
-        NodeKind::NDAddr => {
-            tree[index].ty = Type::Ptr(Box::new(tree[*tree[index].indices.first().unwrap()].ty.clone()));
-        }
         NodeKind::NDDeref => {
             match tree[*tree[index].indices.first().unwrap()].ty.clone() {
-                Type::Ptr(ty1) => tree[index].ty = *ty1,
+                Type::Ptr(ty1) if *unqual(&ty1) == Type::Void => {
+                    eprintln!("void *は参照外しできません");
+                    std::process::exit(1);
+                }
+                Type::Ptr(ty1) | Type::Array(ty1, _) => tree[index].ty = unqual(&ty1).clone(), // 式の値の型は修飾されない
+                Type::Func(func) => tree[index].ty = Type::Func(func), // 関数を指すポインタとして扱う
                 _ => {
                     eprintln!("参照外しができません");
                     std::process::exit(1);
@@ -56,12 +416,15 @@ pub fn type_of_node(tree : &mut Vec<Node>, index : usize) {
             }
         }
         NodeKind::NDRet => {
-            tree[index].ty = tree[*tree[index].indices.first().unwrap()].ty.clone();
+            tree[index].ty = match tree.get(tree[index].indices[0]) {
+                Some(node) => node.ty.clone(),
+                None => Type::Void, // return;
+            };
         }
 
         NodeKind::NDMul => {
             match (&tree[*tree[index].indices.first().unwrap()].ty, &tree[*tree[index].indices.last().unwrap()].ty) {
-                (Type::Int, Type::Int) => tree[index].ty = Type::Int,
+                (lhs, rhs) if is_integer(lhs) && is_integer(rhs) => tree[index].ty = usual_arith_conv(tree, index),
                 _ => {
                     eprintln!("未定義の掛け算を行っています");
                     std::process::exit(1);
@@ -70,7 +433,7 @@ pub fn type_of_node(tree : &mut Vec<Node>, index : usize) {
         }
         NodeKind::NDDiv => {
             match (&tree[*tree[index].indices.first().unwrap()].ty, &tree[*tree[index].indices.last().unwrap()].ty) {
-                (Type::Int, Type::Int) => tree[index].ty = Type::Int,
+                (lhs, rhs) if is_integer(lhs) && is_integer(rhs) => tree[index].ty = usual_arith_conv(tree, index),
                 _ => {
                     eprintln!("未定義の割り算を行っています");
                     std::process::exit(1);
@@ -78,7 +441,8 @@ pub fn type_of_node(tree : &mut Vec<Node>, index : usize) {
             }
         }
         NodeKind::NDEq => {
-            if tree[*tree[index].indices.first().unwrap()].ty == tree[*tree[index].indices.last().unwrap()].ty {
+            if comparable(&tree[*tree[index].indices.first().unwrap()].ty, &tree[*tree[index].indices.last().unwrap()].ty) || compares_with_null(tree, index) {
+                convert_operands(tree, index);
                 tree[index].ty = Type::Int;
             }
             else {
@@ -87,7 +451,8 @@ pub fn type_of_node(tree : &mut Vec<Node>, index : usize) {
             }
         }
         NodeKind::NDNEq => {
-            if tree[*tree[index].indices.first().unwrap()].ty == tree[*tree[index].indices.last().unwrap()].ty {
+            if comparable(&tree[*tree[index].indices.first().unwrap()].ty, &tree[*tree[index].indices.last().unwrap()].ty) || compares_with_null(tree, index) {
+                convert_operands(tree, index);
                 tree[index].ty = Type::Int;
             }
             else {
@@ -96,7 +461,8 @@ pub fn type_of_node(tree : &mut Vec<Node>, index : usize) {
             }
         }
         NodeKind::NDLe => {
-            if tree[*tree[index].indices.first().unwrap()].ty == tree[*tree[index].indices.last().unwrap()].ty {
+            if comparable(&tree[*tree[index].indices.first().unwrap()].ty, &tree[*tree[index].indices.last().unwrap()].ty) {
+                convert_operands(tree, index);
                 tree[index].ty = Type::Int;
             }
             else {
@@ -105,7 +471,8 @@ pub fn type_of_node(tree : &mut Vec<Node>, index : usize) {
             }
         }
         NodeKind::NDLeEq => {
-            if tree[*tree[index].indices.first().unwrap()].ty == tree[*tree[index].indices.last().unwrap()].ty {
+            if comparable(&tree[*tree[index].indices.first().unwrap()].ty, &tree[*tree[index].indices.last().unwrap()].ty) {
+                convert_operands(tree, index);
                 tree[index].ty = Type::Int;
             }
             else {
@@ -113,8 +480,6 @@ pub fn type_of_node(tree : &mut Vec<Node>, index : usize) {
                 std::process::exit(1);
             }
         }
-        _ => {
-            return;
-        }
+        _ => (),
     }
 }
\ No newline at end of file
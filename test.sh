@@ -3,7 +3,7 @@ assert(){
     expected="$1"
     input="$2"
     
-    ./target/debug/mycc "$input" > tmp.s
+    ./target/debug/mycc "${@:3}" "$input" > tmp.s
     cc -o tmp tmp.s
     ./tmp
     ret="$?"
@@ -16,6 +16,16 @@ assert(){
     fi
 }
 
+assert_error(){
+    input="$1"
+
+    if ./target/debug/mycc "${@:2}" "$input" > /dev/null 2>&1; then
+        echo "$input => error expected"
+        exit 1
+    fi
+    echo "$input => error"
+}
+
 assert_func(){
     input="$1"
 
@@ -80,10 +90,336 @@ assert 3 'int main(){ int x; x=3; int y; y=5; return *(&y+1); }'
 assert 7 'int main(){ int x; x=3; int y; y=5; *(&x-1)=7; return y; }'
 assert 7 'int main(){ int x; x=3; int y; y=5; *(&y+1)=7; return x; }'
 assert 6 'int main(){ int x; x=3; int* y; y = &x; int** z; z = &y; int*** w; w = &z; return ***w + 3;}'
+assert 1 'int main(){ int x; int y; return &x - &y; }'
+assert 1 'int main(){ int *a; int *b; return &a - &b; }'
+assert 7 'int main(){ int x; int y; int *p; x = 7; p = &y; return *(1 + p + 1 - 1); }'
+assert_error 'int main(){ int x; return 1 - &x; }'
 assert 4 'int main(){ int x; x=3; return sizeof(x); }'
-assert 4 'int main(){ int x; x=3; return sizeof(sizeof(x)); }'
+assert 8 'int main(){ int x; x=3; return sizeof(sizeof(x)); }'
 assert 8 'int main(){ int* x; return sizeof(x); }'
-assert 12 'int main(){ int* x; return sizeof(x) + sizeof(sizeof x); }'
+assert 16 'int main(){ int* x; return sizeof(x) + sizeof(sizeof x); }'
 assert 4 'int main(){return sizeof(2);}'
-assert 4 'int main(){ int* x; return sizeof(x - x);}'
+assert 8 'int main(){ int* x; return sizeof(x - x);}'
+assert 1 'int main(){ int x; x = 2147483647; x = x + 1; return x < 0; }'
+assert 1 'int main(){ int x; x = 65536; return x * x == 0; }'
+assert 13 'int main(){ int x; x = -7; return -(x * 1) / 2 + 10; }'
+assert 1 'int ret(int a){ return a; } int main(){ return ret(-1) < 0; }'
+assert 19 'int main(){ int a[3]; a[0] = 1; a[1] = 2; a[2] = 4; return a[0] + a[1] + a[2] + sizeof(a); }'
+assert 45 'int main(){ int a[2][3]; int i; int j; for(i = 0; i < 2; i = i + 1) for(j = 0; j < 3; j = j + 1) a[i][j] = i * 3 + j; return a[1][2] + sizeof(a) + sizeof(a[0]) + *(*(a + 1) + 1); }'
+assert 10 'int sum(int *a, int n){ int s; s = 0; int i; for(i = 0; i < n; i = i + 1) s = s + a[i]; return s; } int main(){ int x[4]; int *p; p = x; *p = 1; p[1] = 2; 2[x] = 3; x[3] = 4; return sum(x, 4); }'
+assert 21 'int f(int m[][2]){ return m[1][1]; } int main(){ int m[2][2]; m[1][1] = 9; int *q; q = &m[1][0]; return f(m) + (&m[1][1] - &m[0][0]) + *(q + 1); }'
+assert 5 'int main(){ int a[2]; int b; b = 5; a[0] = 1; a[1] = 2; return b; }'
+assert_error 'int main(){ int a[2]; int b[2]; a = b; }'
+assert 44 'int main(){ char c; c = 300; return c; }'
+assert 1 'int main(){ char c; return (c = 200) < 0; }'
+assert 200 'int main(){ unsigned char c; c = 200; return c; }'
+assert 254 'char f(char c, unsigned char d){ return c + d; } int main(){ return f(-1, 255); }'
+assert 19 'int main(){ char a[3]; int x; x = 7; a[0] = 1; a[1] = 2; a[2] = 3; return a[0] + a[2] + sizeof(a) + x + sizeof(char) + sizeof(signed char) + sizeof "ab"; }'
+assert 12 'int len(char *s){ int n; n = 0; while(s[n]) n = n + 1; return n; } int main(){ return len("hello" " world\n"); }'
+assert 2 'int main(){ char *s; s = "abc"; return s[1] - 96 + *(s + 2) - 99; }'
+assert 64 'int main(){ return "\x41\101"[1] + '"'a'"' - '"'b'"'; }'
+assert 1 'int main(){ unsigned int x; x = 0; return x - 1 > 0; }'
+assert 0 'int main(){ int x; x = -1; unsigned int y; y = 1; return x < y; }'
+assert 1 'int main(){ long x; x = -1; unsigned int y; y = 1; return x < y; }'
+assert 1 'int main(){ int x; x = -7; unsigned y; y = 2; return x / y == 2147483644; }'
+assert 19 'int main(){ long x; x = 4294967296; return x / 4294967296 + sizeof(x) + sizeof(long long) + sizeof(short); }'
+assert 2 'int main(){ short s; s = 65537; unsigned short u; u = -1; return s + (u == 65535); }'
+assert 255 'long f(long a, short b, unsigned char c){ return a * b + c; } int main(){ return f(3000000000, 2, 255) - 6000000000; }'
+assert 1 'int main(){ long long a; unsigned long long b; a = -1; b = a; return (b > 0) + (-2147483648 > 0) + (0xffffffff < 0); }'
+assert 3 'void f(int *p){ *p = 3; return; } int main(void){ int x; f(&x); return x; }'
+assert 7 'int main(){ int x; void *p; int *q; p = &x; q = p; *q = 5; return x + (p == q) + sizeof(void); }'
+assert 9 'void *g(void *p){ return p + 1; } int main(){ char a[2]; a[1] = 9; char *c; c = g(a); return *c; }'
+assert_error 'void f(){ return 1; } int main(){ return 0; }'
+assert 2 'int main(){ _Bool b; b = 256; return b + sizeof(b); }'
+assert 1 '_Bool f(int x){ return x; } int main(){ return f(512) + f(0); }'
+assert 11 'int main(){ int x; _Bool b; b = &x; _Bool c; c = -1; c = c + 1; return b + c * 10; }'
+assert 2 'int main(){ char *p; p = 0; if (p) return 1; long x; x = 4294967296; if (x) return 2; return 3; }'
+assert 3 '#include <stdbool.h>
+int main(){ bool t; t = 2; bool f; f = false; return (t == true) + (f == 0) + t; }'
+assert 166 'struct P { char c; int x; long y; }; int main(){ struct P p; p.c = 1; p.x = 2; p.y = 3; return p.c + p.x + p.y + sizeof(p) * 10; }'
+assert 143 'struct node { int val; struct node *next; }; int main(){ struct node a; struct node b; a.val = 3; b.val = 4; a.next = &b; b.next = &a; struct node *p; p = &a; return p->val + p->next->val * 10 + (p->next->next == p) * 100; }'
+assert 63 'int main(){ struct { char a; short b; char c; } s; struct { char x[3]; } t; return sizeof(s) * 10 + sizeof(t); }'
+assert 16 'int main(){ struct S { int a; char b[5]; } x; struct S y; x.a = 7; x.b[4] = 9; y = x; x.a = 1; return y.a + y.b[4]; }'
+assert 18 'int main(){ struct A { struct B { int x; int y; } b; int z; } a; a.b.y = 5; a.z = 2; struct B *q; q = &a.b; return q->y * a.z + sizeof(struct B); }'
+assert 16 'int main(){ struct S { int x; }; { struct S { long y; long z; }; return sizeof(struct S); } }'
+assert 4 'struct S; int f(struct S *p){ return 1; } struct S { int a; int b; }; int main(){ struct S s; s.b = 3; return f(&s) + s.b; }'
+assert 33 'int main(){ struct S { int a, *b, c[2]; } s; int x; x = 4; s.b = &x; s.c[1] = 5; return *s.b + s.c[1] + sizeof(s); }'
+assert 37 'struct __attribute__((packed)) P { char c; int x; }; struct Q { char c; long x; } __attribute__((aligned(32))); int main(){ return sizeof(struct P) + sizeof(struct Q); }'
+assert 68 '#pragma pack(push, 2)
+struct P { char c; int x; };
+#pragma pack(pop)
+struct Q { char c; int x; };
+int main(){ return sizeof(struct P) * 10 + sizeof(struct Q); }'
+assert_error 'int main(){ struct S { int x; } a; return a.y; }'
+assert_error 'int main(){ struct S a; return 0; }'
+assert 10 'union U { char c; int i; long l; }; int main(){ union U u; u.l = 0; u.i = 258; return u.c + sizeof(u); }'
+assert 84 'int main(){ union { char a[5]; int b; } u; struct S { char c; union { short s; char d; } v; } x; return sizeof(u) * 10 + sizeof(x); }'
+assert 8 'int main(){ union U { int i; char c[4]; } a; union U b; a.i = 0; a.c[1] = 1; b = a; a.i = 7; return b.i / 256 + a.i; }'
+assert 9 'union N { int v; union N *p; }; int main(){ union N a; union N b; b.v = 9; a.p = &b; return a.p->v; }'
+assert_error 'union U { int x; }; int main(){ struct U a; return 0; }'
+assert 60 'enum Color { RED, GREEN = 5, BLUE, }; int main(){ enum Color c; c = BLUE; return c + RED * 100 + GREEN * 10 + sizeof(c); }'
+assert 62 'enum { N = 2 + 3 * 2, M = N * 2 - (N == 8) }; int main(){ int a[N]; char b[M][2]; return sizeof(a) + sizeof(b); }'
+assert 11 'enum E { A = -1, B, C }; int main(){ enum E e; e = A; if (e < B) return C + 10; return 0; }'
+assert 176 'enum { X = 3 }; int main(){ int r; r = X; { enum { X = 7 }; r = r + X * 10; } { int X; X = 1; r = r + X * 100; } return r + X; }'
+assert 20 'enum { SZ = sizeof(long) * 2, BIG = 4000000000u / 1000000000 }; int main(){ struct S { char c; } __attribute__((aligned(SZ))) s; return sizeof(s) + BIG; }'
+assert 1 'enum { U = -1 < 0u, V = -1 < 0 }; int main(){ return U * 10 + V; }'
+assert_error 'enum { A = 2147483647, B }; int main(){ return 0; }'
+assert_error 'int main(){ int x; x = 1; enum { A = x }; return 0; }'
+assert 7 'typedef int T; int main(){ T x; x = 3; T * p; p = &x; return *p + sizeof(T); }'
+assert 12 'typedef int T; int main(){ int T; int x; T = 6; x = 2; T * x; return T * x; }'
+assert 10 'typedef struct Node { int v; struct Node *next; } Node, *NodePtr; int main(){ Node a; Node b; NodePtr p; a.v = 1; b.v = 2; a.next = &b; p = &a; return p->next->v + sizeof(NodePtr); }'
+assert 47 'typedef char Buf[4]; typedef long L; int main(){ Buf b; b[3] = 7; L x; x = (L)(b[3]); return x + sizeof(Buf) * 10; }'
+assert 93 'typedef int T; int main(){ long y; y = 300; int x; x = (T)(y); { typedef char T; x = x + sizeof(T) + (T)y; } return x + sizeof(T); }'
+assert 7 'typedef int T; typedef int T; int f(T a, T *b){ return a + *b; } int main(){ T x; x = 4; return f(3, &x); }'
+assert 255 'int main(){ unsigned x; x = (unsigned)-1; char c; c = (char)x; return (c == -1) + (int)(unsigned char)x - 1; }'
+assert 40 '#include <stddef.h>
+struct S { char c; long l; int i; };
+int main(){ size_t n; n = sizeof(struct S); int *p; p = NULL; if (p == NULL) return n + offsetof(struct S, i); return 0; }'
+assert 63 '#include <stdint.h>
+int main(){ int8_t a; uint8_t b; uint16_t c; int64_t d; a = 200; b = 200; c = 70000; d = -1; return (a < 0) + (b > 0) * 2 + (c == 4464) * 4 + (d < 0) * 8 + (sizeof(uintptr_t) == 8) * 16 + (INT32_MAX == 2147483647) * 32; }'
+assert_error 'typedef int T; typedef char T; int main(){ return 0; }'
+assert 7 'int add(int a, int b); int main(){ return add(3, 4); } int add(int a, int b){ return a + b; }'
+assert 45 'char f(char c); int main(){ return f(300); } char f(char c){ return c + 1; }'
+assert 10 'long g(long x, unsigned char y){ return x + y; } int main(){ return g(-1, 257) + 10; }'
+assert 42 'int f(); int f(int x){ return x * 2; } int main(){ return f(21); }'
+assert 5 'int h(int *p, ...); int main(){ int x; x = 5; return h(&x, 1, 2); } int h(int *p, ...){ return *p; }'
+assert 10 'void *v(void *p){ return p; } int main(){ int x; x = 9; int *q; q = v(&x); return *q + (v(q) == q); }'
+assert 3 'int f(int, char *); int main(){ char c; c = 2; return f(1, &c); } int f(int a, char *b){ return a + *b; }'
+assert_error 'int f(int a); int main(){ return f(1, 2); }'
+assert_error 'int f(int *a); int main(){ return f(3); }'
+assert_error 'int f(int a); long f(int a){ return 0; } int main(){ return 0; }'
+assert 10 'int add(int a, int b){ return a + b; } int main(){ int (*fp)(int, int); fp = add; return fp(1, 2) + (*fp)(3, 4); }'
+assert 11 'int add(int a, int b){ return a + b; } int main(){ int (*fp)(int, int); fp = &add; return (**fp)(5, 6) + (fp == add) - 1; }'
+assert 14 'int inc(int x){ return x + 1; } int dbl(int x){ return x * 2; } int main(){ int (*fs[2])(int); fs[0] = inc; fs[1] = dbl; return fs[0](3) + fs[1](5); }'
+assert 85 'int apply(int (*f)(int), int x){ return f(x); } int twice(int g(int), int x){ return g(g(x)); } int sq(int x){ return x * x; } int main(){ return apply(sq, 7) + twice(sq, 2) + 20; }'
+assert 16 'struct ops { int (*cb)(int); int v; }; int neg(int x){ return 10 - x; } int main(){ struct ops s; struct ops *p; s.cb = neg; s.v = 3; p = &s; return s.cb(s.v) + p->cb(1); }'
+assert 21 'typedef int (*binop)(int, int); int sub(int a, int b){ return a - b; } int main(){ binop f; f = sub; return f(9, 4) + sizeof(binop) + sizeof(int (*)(int)); }'
+assert 43 'typedef int fn_t(int); int twice(int x){ return x * 2; } char tochar(int x){ return x; } int main(){ fn_t *f; char (*c)(int); f = twice; c = tochar; return f(21) + (c(300) == 44); }'
+assert 33 'int *id(int *p){ return p; } int main(){ int x; int *(*g)(int *); int (*p)[3]; int a[3]; x = 5; a[2] = 8; g = id; p = &a; return *g(&x) + (*p)[2] + sizeof(p) + sizeof(*p); }'
+assert_error 'int main(){ int x; x = 1; return x(2); }'
+assert_error 'int f(int x){ return x; } int main(){ int (*fp)(int); fp = f; return fp(1, 2); }'
+assert 11 'int main(){ const int x; int *p; const int *q; int a; a = 3; q = &a; p = &a; return *q + sizeof(const int) + sizeof(x); }'
+assert 14 'int main(){ int a[3]; const int *p; int *const q; a[1] = 7; p = a; return p[1] + *(p + 1); }'
+assert 7 'int len(const char *s){ int n; n = 0; while (s[n]) n = n + 1; return n; } int main(){ char *s; s = "hello"; return len(s) + len("ab"); }'
+assert 12 'struct P { const int x; int y; }; int main(){ struct P p; struct P *q; q = &p; q->y = 4; return q->y + sizeof(struct P); }'
+assert 20 'typedef const int CI; int main(){ CI a; volatile int v; int volatile unsigned long w; v = 5; w = 3; return v + w + sizeof(CI) + sizeof(w); }'
+assert 12 'int f(int *restrict p, const int n){ *p = n; return n; } int main(){ int x; char *restrict s; return f(&x, 6) + x; }'
+assert 7 'int g(const int x){ return x + 1; } int g(int x); int main(){ int x; int *p; const int *const *cpp; int *const *pp; int (*fp)(int); p = &x; x = 2; pp = &p; cpp = 0; fp = g; return fp(4) + **pp + (cpp != 0); }'
+assert_error 'int main(){ const int x; x = 1; return 0; }'
+assert_error 'int main(){ int a; const int *p; p = &a; *p = 1; return 0; }'
+assert_error 'struct S { int x; }; int main(){ struct S s; const struct S *p; p = &s; p->x = 1; return 0; }'
+assert_error 'int main(){ const int a; int *p; p = &a; return 0; }'
+assert_error 'int f(char *s){ return 0; } int main(){ const char *c; return f(c); }'
+assert 74 '#define str(s) # s
+int main(){ char *p; p = str(: @\n); return p[2] + p[3]; }'
+assert 3 "#if 0
+it's skipped
+#endif
+int main(){ return 3; }"
+assert_error 'int main(){ return 0; } @'
+assert_error "int main(){ return 'a; }"
+assert 5 '#define FOO 1
+#define HAS_FOO defined(FOO)
+#define HAS_BAR defined BAR
+#if HAS_FOO && !HAS_BAR
+int main(){ return 5; }
+#endif'
+assert 7 'struct S { char a; int b; };
+int main(){ int x; x = 1; if (x)
+#pragma pack(1)
+ x = sizeof(struct S) - 1; else
+#pragma pack()
+ x = 0; while (x > 7)
+#pragma pack(2)
+ x = x - 1; return x; }'
+assert_error '#define F(a, ...) __VA_OPT__(## a)
+int main(){ return F(1, 2); }'
+assert_error '#define F(a, ...) __VA_OPT__(a ##)
+int main(){ return F(1, 2); }'
+assert 0 "$(for header in include/*.h; do echo "#include <${header#include/}>"; done)
+int main(){ return 0; }" -ffreestanding
+assert 15 '#include <stdarg.h>
+int sum(int n, ...){ va_list ap; va_start(ap, n); int s; s = 0; while (n > 0) { s = s + va_arg(ap, int); n = n - 1; } va_end(ap); return s; }
+int main(){ return sum(5, 1, 2, 3, 4, 5); }'
+assert 108 '#include <stdarg.h>
+int pick(int i, va_list ap){ va_list aq; va_copy(aq, ap); int v; v = 0; while (i >= 0) { v = va_arg(aq, int); i = i - 1; } va_end(aq); return v; }
+int f(char *fmt, ...){ va_list ap; va_start(ap, fmt); int r; r = pick(1, ap) * 10 + pick(0, ap); va_arg(ap, int); va_arg(ap, int); r = r + *va_arg(ap, char *); va_end(ap); return r; }
+int main(){ return f("x", 3, 4, "A"); }'
+assert 45 '#include <stdalign.h>
+#include <stdnoreturn.h>
+#include <stddef.h>
+struct S { char c; alignas(8) char d; _Alignas(int) char e; };
+noreturn void die(void);
+_Noreturn int f(void){ return 1; }
+int main(){ char a; alignas(16) char b; long x; x = (long)&b; return sizeof(struct S) + alignof(struct S) + offsetof(struct S, e) + _Alignof(long) + (x - x / 16 * 16) + _Alignof a; }'
+assert_error '#include <stdarg.h>
+int f(int a){ va_list ap; va_start(ap, a); return 0; } int main(){ return 0; }'
+assert_error 'int main(){ _Alignas(3) int x; return 0; }'
+assert 27 'struct N { struct N *next; int v; }; int main(){ struct N a; struct N b; struct N *p; int n; a.next = &b; b.next = 0; n = 0; p = &a; while (p != 0) { n = n + 1; p = p->next; } return n * 10 + (p == 0) + (0 == p) * 2 + (&a != 0) * 4; }'
+assert_error 'int main(){ int *p; return p == 1; }'
+assert 3 'int add(int a, int b){ return a + b; } int (*get(void))(int, int){ return add; } int main(){ return get()(1, 2); }'
+assert 3 'int add(int a, int b){ return a + b; } int (*get(int n))(int, int); int main(){ return get(4)(1, 2); } int (*get(int n))(int, int){ if (n == 4) return add; return 0; }'
+assert 5 'int (*arr(int (*p)[2]))[2]{ return p; } int main(){ int x[2]; (*arr(&x))[1] = 5; return x[1]; }'
+assert_error 'int (*get)(int); int main(){ return 0; }'
+assert 5 'int main(){ const int x = 5; return x; }'
+assert 4 'int main(){ int a[3]; a[1] = 4; int *const p = a; return p[1]; }'
+assert 7 'struct S { int a; int b; }; int main(){ struct S t; t.a = 1; t.b = 6; const struct S s = t; return s.a + s.b; }'
+assert 6 'int main(){ const int x = 5; const int *p = &x; return *p + 1; }'
+assert_error 'int main(){ const int x = 5; x = 3; return x; }'
+assert_error 'int main(){ const int x = 5; int *p = &x; return 0; }'
+assert_error 'int main(){ int a[2]; int *const p = a; p = a; return 0; }'
+assert 3 'const int *f(const int *p){ return p; } int main(){ int x = 3; return *f(&x); }'
+assert 4 'const int *f(int *p){ return p; } int main(){ int x = 4; return *f(&x); }'
+assert_error 'int *f(const int *p){ return p; } int main(){ return 0; }'
+assert 5 '#if __has_include("/nonexistent/foo.h") || !__has_include("/usr/include/stdio.h")
+#error absolute path
+#endif
+int main(){ return 5; }'
+assert_error '#include "/nonexistent/foo.h"
+int main(){ return 0; }'
+assert 19 '#include <stddef.h>
+int main(){ size_t *p; size_t n; p = &n; n = sizeof(int); return (sizeof(int) - 5 > 0) * 10 + sizeof(_Alignof(int)) + (*p == 4); }'
+assert 14 'int main(){ int a[4]; int *p; int *q; p = &a[3]; q = a; return (sizeof(p - q) == 8) * 10 + (p - q) + (q - p == -3); }'
+assert 1 'int main(){ long x; char *p; char *q; x = 4294967296; p = (char *)x; q = (char *)0; return p - q == 4294967296; }'
+assert_error 'struct S { int a; }; int main(){ int x; struct S s; x = s; return 0; }'
+assert_error 'struct S { int a; }; struct T { int a; int b; }; int main(){ struct S s; struct T t; t = s; return 0; }'
+assert_error 'struct S { int a; }; int main(){ struct S s; s = 1; return 0; }'
+assert 1 'int main(){ int x; x = 1; { int x; x = 5; } return x; }'
+assert 32 'int main(){ int r; r = 0; { int y; y = 2; r = r + y; } { int y; y = 3; r = r + y * 10; } return r; }'
+assert 5 'int main(){ int x; x = 1; { char x; x = 300; { int x; x = 7; } if (x != 44) return 1; } return x + sizeof(x); }'
+assert 4 'int f(int x){ { int x; x = 9; } return x; } int main(){ return f(4); }'
+assert_error 'int main(){ { int x; x = 3; } return x; }'
+assert_error 'int main(){ int x; int x; return 0; }'
+assert 7 'int main(){ return foo() + 1; } int foo(){ return 6; }'
+assert 8 'int main(){ int x; x = foo(); return (foo() == 7) + x; } int foo(){ return 7; }'
+assert 7 'int main(){ return add(3, 4); } int add(int a, int b){ return a + b; }'
+assert_error 'int main(){ return foo(); } long foo(){ return 6; }'
+assert 21 'int f(int a, int b, int c, int d, int e, int g){ return a + b + c + d + e + g; } int main(){ int (*fp)(int, int, int, int, int, int); fp = f; return fp(1, 2, 3, 4, 5, 6); }'
+assert_error 'int f(); int main(){ return f(1, 2, 3, 4, 5, 6, 7); }'
+assert_error 'int f(int a, int b, int c, int d, int e, int g, int h); int main(){ int (*fp)(int, int, int, int, int, int, int); fp = f; return fp(1, 2, 3, 4, 5, 6, 7); }'
+assert_error 'int f(int a, int b, int c, int d, int e, int g, int h){ return h; } int main(){ return 0; }'
+assert 3 '#define ONE 1
+#define ADD(a, b) ((a) + (b))
+int main(){ return ADD(ONE, 2); }'
+assert 9 '#define ADD(a, b) ((a) + (b))
+#define TWICE(x) ADD(x, x) + x
+int main(){ return TWICE(3); }'
+assert 5 '#define CAT(a, b) a ## b
+int main(){ int xy; xy = 5; return CAT(x, y); }'
+assert 12 '#define CAT(a, b) a##b
+int main(){ return CAT(1, ) + CAT(, 11); }'
+assert 3 'int main(){ int q; q = 2;
+#define q (q + 1)
+return q; }'
+assert 4 '#define X 4
+int main(){
+#undef X
+int X; X = 4; return X; }'
+assert 1 '#define A 3
+#if defined(A) && A > 2 && !defined B && UNDEFINED == 0
+int main(){ return 1; }
+#else
+int main(){ return 2; }
+#endif'
+assert 3 '#if 0
+#if garbage (
+#endif
+int main(){ return 1; }
+#elif 0 && 1 / 0
+int main(){ return 2; }
+#else
+int main(){ return 3; }
+#endif'
+assert 2 '#ifdef A
+int main(){ return 1; }
+#elif -1 > 0u && (1 ? 2 : 1 / 0) << 2 == 8
+int main(){ return 2; }
+#endif'
+assert 4 '#define B
+#ifndef B
+int main(){ return 1; }
+#elif 0x7fffffffffffffff + 1 < 0 && 0xffffffffffffffff == -1 && -7 / 2 == -3
+int main(){ return 4; }
+#endif'
+assert 3 'int main(){
+return __LINE__ + __COUNTER__ + __COUNTER__; }'
+assert 1 '#if __STDC__ && __STDC_VERSION__ >= 201112L && __x86_64__ && __LP64__ && __linux__
+int main(){ return 1; }
+#endif'
+assert 8 'int main(){ return FOO + BAR + SQ(2); }' -DFOO -DBAR=3 '-DSQ(x)=x*x'
+assert 2 '#ifdef __linux__
+int main(){ return 1; }
+#else
+int main(){ return 2; }
+#endif' -U__linux__
+assert 7 "$(./target/debug/mycc -E '#define ADD(a, b) a + b
+int main(){ int x; x = 4;
+    return ADD(x,
+               3); }')"
+printf '#ifndef TMP_GUARD_H\n#define TMP_GUARD_H\nint guarded(){ return 2; }\n#endif\n' > tmp_guard.h
+printf '#pragma once\nint once(){ return 1; }\n' > tmp_once.h
+assert 3 '#include "tmp_guard.h"
+#include "tmp_once.h"
+#include "tmp_guard.h"
+#include "./tmp_once.h"
+int main(){ return guarded() + once(); }'
+assert 100 '#line 100 "generated.y"
+int main(){ return __LINE__; }'
+assert 5 '#pragma pack(push, 1)
+#pragma unknown_pragma
+int main(){
+#pragma pack(pop)
+  return 5;
+#pragma pack()
+}'
+assert 3 '#if defined(__has_include) && __has_include("tmp_once.h") && !__has_include(<no_such_header.h>)
+#define HAS 1
+#endif
+#define CHECK(x) __has_builtin(x)
+#if CHECK(__builtin_expect) && !__has_builtin(__builtin_no_such) && !__has_attribute(no_such)
+int main(){ return __builtin_expect(HAS + 2, 1); }
+#endif'
+assert 15 '#define CALL(f, ...) f(__VA_ARGS__)
+#define LOG(fmt, ...) sum(fmt, ## __VA_ARGS__)
+int sum(int a){ return a; }
+int sum3(int a, int b, int c){ return a + b + c; }
+int main(){ return CALL(sum3, 1, (2), 3) + LOG(9); }'
+assert 6 '#define ARGS(a, rest...) a + rest
+#define OPT(x, ...) x __VA_OPT__(+ __VA_ARGS__)
+int main(){ return ARGS(1, 2) + OPT(3) + OPT(0, 0); }'
+assert 7 '#define ADD(a, b) \
+    ((a) + \
+     (b))
+%:define CAT(a, b) a %:%: b
+int main()<% int x\
+y = 4; re\
+turn ADD(CAT(x, y), 3); %>'
+assert 3 '#include <limits.h>
+#include <stdbool.h>
+#include <stdalign.h>
+#include <stdnoreturn.h>
+#if LLONG_MIN < 0 && ULLONG_MAX == 18446744073709551615U && defined(__alignas_is_defined)
+int main(){ return (INT_MAX == 2147483647) + (CHAR_BIT == 8) + true + false + __STDC_HOSTED__; }
+#endif' -ffreestanding
+printf '#include "tmp_guard.h"\n#include <stddef.h>\nint main(){ return guarded(); }\n' > tmp_dep.c
+deps="$(./target/debug/mycc -MM -MP -MT tmp tmp_dep.c)"
+if [ "$deps" != "$(printf 'tmp: tmp_dep.c tmp_guard.h\n\ntmp_guard.h:')" ]; then
+    echo "-MM => unexpected output: $deps"
+    exit 1
+fi
+./target/debug/mycc -MD -MF tmp.d "$(printf '#include "tmp_once.h"\nint main(){ return once(); }')" > /dev/null
+if ! grep -q 'tmp_once.h' tmp.d; then
+    echo "-MD => tmp.d does not list tmp_once.h"
+    exit 1
+fi
+prefix="$(mktemp -d)"
+mkdir -p "$prefix/bin" "$prefix/lib/mycc"
+cp ./target/debug/mycc "$prefix/bin/"
+cp -r include "$prefix/lib/mycc/"
+if ! "$prefix/bin/mycc" '#include <stdbool.h>
+int main(){ return true; }' > /dev/null 2>&1; then
+    echo "installed mycc => bundled headers not found"
+    exit 1
+fi
+rm -rf "$prefix"
 echo OK
\ No newline at end of file